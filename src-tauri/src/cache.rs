@@ -0,0 +1,144 @@
+//! Binary cache of parsed export XML files, so large plates only have to be
+//! parsed once. Entries live in the app cache dir and are keyed by the path,
+//! size and modification time of the XML file. The least recently used
+//! entries are removed once they take up more than `MAX_CACHE_BYTES`.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::parse_xml::Harmony;
+
+/// Bump whenever `Harmony` (or anything it holds) changes shape
+const CACHE_VERSION: u32 = 5;
+
+/// Space cache entries can take up before the least recently used are removed
+const MAX_CACHE_BYTES: u64 = 2_000_000_000;
+
+#[derive(PartialEq, serde::Serialize, serde::Deserialize)]
+struct CacheKey {
+    version: u32,
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+impl CacheKey {
+    fn new(xml: &Path) -> Result<Self> {
+        let path = xml.canonicalize().context("resolving XML path")?;
+        let meta = fs::metadata(&path).context("reading XML metadata")?;
+
+        Ok(Self {
+            version: CACHE_VERSION,
+            path,
+            size: meta.len(),
+            modified: meta.modified().context("reading XML modification time")?,
+        })
+    }
+
+    fn cache_file(&self, dir: &Path) -> PathBuf {
+        let mut h = DefaultHasher::new();
+        self.path.hash(&mut h);
+        dir.join(format!("harmony-{:016x}.bin", h.finish()))
+    }
+}
+
+/// Get the cached parse of `xml`, if there is one that is still up to date
+pub fn load(dir: &Path, xml: &Path) -> Option<Harmony> {
+    let key = CacheKey::new(xml).ok()?;
+    let path = key.cache_file(dir);
+    let f = File::open(&path).ok()?;
+    let mut rdr = BufReader::new(f);
+
+    let cached: CacheKey = bincode::deserialize_from(&mut rdr).ok()?;
+    if cached != key {
+        return None;
+    }
+
+    let hm = bincode::deserialize_from(&mut rdr).ok()?;
+    // eviction goes by modification time, so mark it as used
+    if let Err(e) = File::options()
+        .write(true)
+        .open(&path)
+        .and_then(|f| f.set_modified(SystemTime::now()))
+    {
+        tracing::debug!(path = %path.display(), error = %e, "failed to mark cache file as used");
+    }
+    Some(hm)
+}
+
+/// Save the parse of `xml` to the cache dir, replacing any older entry
+pub fn store(dir: &Path, xml: &Path, hm: &Harmony) -> Result<()> {
+    let key = CacheKey::new(xml)?;
+    let output = key.cache_file(dir);
+    let temp = output.with_extension("bin.tmp");
+
+    fs::create_dir_all(dir).context("creating cache dir")?;
+
+    let mut w = File::create(&temp)
+        .map(BufWriter::new)
+        .with_context(|| format!("creating cache file <{}>", temp.display()))?;
+    bincode::serialize_into(&mut w, &key)
+        .and_then(|_| bincode::serialize_into(&mut w, hm))
+        .context("writing cache file")?;
+    w.into_inner()
+        .map_err(|e| e.into_error())
+        .context("flushing cache file")?;
+
+    if let Err(e) = fs::rename(&temp, &output) {
+        let _ = fs::remove_file(&temp);
+        bail!("moving cache file into place: {}", e);
+    }
+
+    if let Err(e) = evict(dir, &output) {
+        tracing::warn!(error = ?e, "failed to clean up the parse cache");
+    }
+
+    Ok(())
+}
+
+/// Remove the least recently used entries in `dir`, other than `keep`, until
+/// they fit in `MAX_CACHE_BYTES`
+fn evict(dir: &Path, keep: &Path) -> Result<()> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(dir).context("listing cache dir")? {
+        let entry = entry.context("listing cache dir")?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !(name.starts_with("harmony-") && name.ends_with(".bin")) {
+            continue;
+        }
+        let meta = entry.metadata().context("reading cache file metadata")?;
+        let used = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        entries.push((used, meta.len(), entry.path()));
+    }
+
+    let mut total: u64 = entries.iter().map(|&(_, size, _)| size).sum();
+    entries.sort();
+    for (_, size, path) in entries {
+        if total <= MAX_CACHE_BYTES {
+            break;
+        }
+        if path == keep {
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => {
+                tracing::info!(path = %path.display(), bytes = size, "evicted cached parse");
+                total -= size;
+            }
+            Err(e) => {
+                tracing::warn!(path = %path.display(), error = %e, "failed to evict cached parse")
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,272 @@
+//! Headless use, for rerunning saved export jobs from scripts
+//!
+//! ```text
+//! harmony-dl run <job.json> [--xml <Index.xml>] [--out <dir>] [--filter <expr>]
+//!                           [--network <settings.json>] [--spool-dir <dir>] [--dry-run]
+//! harmony-dl validate <Index.xml>
+//! harmony-dl watch <dir> <job.json> --out <dir> [--interval <seconds>]
+//!                                   [--network <settings.json>] [--spool-dir <dir>]
+//! ```
+
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::{
+    job::ExportJob,
+    logging,
+    net::{self, NetSettings},
+    parse_xml::load_harmony,
+    process::{self, plan_export, run_export, DownloadPlan, Events, FilterExpr},
+    source,
+    validate::ValidationReport,
+    watch::{watch, WatchConfig, LOG_NAME},
+};
+
+const USAGE: &str = "\
+Usage:
+    harmony-dl                      open the app
+    harmony-dl run <job.json>       run a saved export job
+        --xml <Index.xml>           export from this plate instead of the one in the job
+        --out <dir>                 write into this directory instead of the one in the job
+        --filter <expr>             only export images matching <expr>,
+                                    e.g. \"field in 1..9/2 and not edge\"
+        --network <settings.json>   use these network settings, e.g. a bandwidth limit,
+                                    proxy or login for the Harmony server, instead
+                                    of the ones saved in the app
+        --spool-dir <dir>           decompress .tar.gz exports here instead of the
+                                    system temp folder
+        --dry-run                   list what would be written and how much space
+                                    it needs, without downloading anything
+    harmony-dl validate <Index.xml> check an export for missing or duplicate images
+    harmony-dl watch <dir> <job.json>
+                                    run a job on every new export that shows up in <dir>
+        --out <dir>                 write outputs here, in folders mirroring <dir>
+        --interval <seconds>        how often to look for new exports [default: 60]
+        --network <settings.json>   use these network settings instead of the app's
+        --spool-dir <dir>           decompress .tar.gz exports here instead of the
+                                    system temp folder
+";
+
+/// Run the command given on the command line, returning the exit code.
+/// `None` means there was no command, and the app should open instead.
+pub fn run_from_args() -> Option<i32> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let cmd = args.first().map(String::as_str)?;
+    // added by macOS when the app is opened from Finder
+    if cmd.starts_with("-psn_") {
+        return None;
+    }
+
+    attach_console();
+    if matches!(cmd, "run" | "validate" | "watch") {
+        logging::init(None);
+    }
+
+    let res = match cmd {
+        "run" => run_job(&args[1..]),
+        "validate" => validate(&args[1..]),
+        "watch" => watch_dir(&args[1..]),
+        "help" | "--help" | "-h" => {
+            print!("{}", USAGE);
+            Ok(())
+        }
+        other => {
+            eprint!("Unknown command <{}>\n\n{}", other, USAGE);
+            return Some(2);
+        }
+    };
+
+    match res {
+        Ok(()) => Some(0),
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            Some(1)
+        }
+    }
+}
+
+/// Release builds on Windows don't get a console, so output would go nowhere
+/// unless it's sent to the one the command was run from
+#[cfg(windows)]
+fn attach_console() {
+    extern "system" {
+        fn AttachConsole(process_id: u32) -> i32;
+    }
+    const ATTACH_PARENT_PROCESS: u32 = u32::MAX;
+
+    // fails when there is no parent console, e.g. when run from Explorer,
+    // which is fine since there is nowhere to print to then anyway
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+
+#[cfg(not(windows))]
+fn attach_console() {}
+
+/// Value following the flag at `args[i]`
+fn flag_value(args: &[String], i: usize) -> Result<&str> {
+    args.get(i + 1)
+        .map(String::as_str)
+        .ok_or_else(|| anyhow!("Missing value for <{}>\n\n{}", args[i], USAGE))
+}
+
+fn run_job(args: &[String]) -> Result<()> {
+    let path = args
+        .first()
+        .ok_or_else(|| anyhow!("Missing job file\n\n{}", USAGE))?;
+    let mut job = ExportJob::load(path.as_ref())?;
+
+    let mut dry_run = false;
+    let mut network = None;
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--dry-run" => {
+                dry_run = true;
+                i += 1;
+                continue;
+            }
+            "--xml" => job.xml = PathBuf::from(flag_value(args, i)?),
+            "--out" => job.output.dir = PathBuf::from(flag_value(args, i)?),
+            "--filter" => {
+                let expr: FilterExpr = flag_value(args, i)?.parse()?;
+                job.filter.expr = Some(match job.filter.expr.take() {
+                    Some(prev) => FilterExpr::And(vec![prev, expr]),
+                    None => expr,
+                });
+            }
+            "--network" => network = Some(NetSettings::read(flag_value(args, i)?.as_ref())?),
+            "--spool-dir" => source::configure_spool_dir(Some(flag_value(args, i)?.into())),
+            other => bail!("Unknown option <{}>\n\n{}", other, USAGE),
+        }
+        i += 2;
+    }
+
+    let hm = job.load_export(None)?;
+
+    if dry_run {
+        let plan = plan_export(&hm, &job.filter, &job.output)?;
+        print_plan(&plan);
+        return Ok(());
+    }
+
+    configure_network(network)?;
+    run_export(&hm, &job.filter, &job.output, progress_events())?;
+    eprintln!("Finished export to <{}>", job.output.dir.display());
+
+    Ok(())
+}
+
+fn print_plan(plan: &DownloadPlan) {
+    let size = |bytes: Option<u64>| match bytes {
+        Some(b) => format!("{:.1} GB", b as f64 / 1e9),
+        None => "unknown".to_string(),
+    };
+
+    for out in &plan.outputs {
+        println!("{}", out.display());
+    }
+    eprintln!(
+        "{} images to download ({}), {} outputs to write ({})",
+        plan.images,
+        size(plan.download_bytes),
+        plan.outputs.len(),
+        size(plan.disk_bytes)
+    );
+    if !plan.conflicts.is_empty() {
+        eprintln!(
+            "{} outputs already exist, which will be handled with {:?}",
+            plan.conflicts.len(),
+            plan.existing
+        );
+    }
+}
+
+fn validate(args: &[String]) -> Result<()> {
+    let path = args
+        .first()
+        .ok_or_else(|| anyhow!("Missing XML file\n\n{}", USAGE))?;
+
+    let hm = load_harmony(path.as_ref(), None, |_, _| ())
+        .with_context(|| format!("reading <{}>", path))?;
+    let report = ValidationReport::new(&hm);
+    print!("{}", report);
+
+    if !report.is_ok() {
+        bail!("Found problems in <{}>", path);
+    }
+    Ok(())
+}
+
+fn watch_dir(args: &[String]) -> Result<()> {
+    let (dir, job) = match args {
+        [dir, job, ..] => (PathBuf::from(dir), ExportJob::load(job.as_ref())?),
+        _ => bail!("Missing folder or job file\n\n{}", USAGE),
+    };
+
+    let mut out_root = None;
+    let mut interval = Duration::from_secs(60);
+    let mut network = None;
+    let mut i = 2;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--out" => out_root = Some(PathBuf::from(flag_value(args, i)?)),
+            "--interval" => {
+                let secs = flag_value(args, i)?
+                    .parse()
+                    .context("parsing interval as seconds")?;
+                interval = Duration::from_secs(secs);
+            }
+            "--network" => network = Some(NetSettings::read(flag_value(args, i)?.as_ref())?),
+            "--spool-dir" => source::configure_spool_dir(Some(flag_value(args, i)?.into())),
+            other => bail!("Unknown option <{}>\n\n{}", other, USAGE),
+        }
+        i += 2;
+    }
+    let out_root = out_root.ok_or_else(|| anyhow!("Missing <--out>\n\n{}", USAGE))?;
+    configure_network(network)?;
+
+    eprintln!(
+        "Watching <{}>, processed exports are logged in <{}>",
+        dir.display(),
+        out_root.join(LOG_NAME).display()
+    );
+    let cfg = WatchConfig {
+        dir,
+        job,
+        out_root,
+        interval,
+    };
+
+    watch(&cfg, progress_events, |index, res| match res {
+        Ok(()) => eprintln!("Exported <{}>", index.display()),
+        Err(e) => eprintln!("Failed to export <{}>: {:?}", index.display(), e),
+    })
+}
+
+/// Use the network settings given with `--network`, or else the ones saved in the app
+fn configure_network(settings: Option<NetSettings>) -> Result<()> {
+    let settings = settings.unwrap_or_else(|| NetSettings::load(net::app_settings_file()));
+    net::configure(&settings)
+}
+
+/// Download events that print the progress to stderr
+fn progress_events() -> Events {
+    process::progress_events(|p| {
+        let eta = p
+            .eta_secs
+            .map(|s| format!(", {:.0} min left", s / 60.0))
+            .unwrap_or_default();
+        eprintln!(
+            "{}/{} images, {}/{} outputs, {:.1} MB/s{}",
+            p.images,
+            p.total_images,
+            p.outputs,
+            p.total_outputs,
+            p.bytes_per_sec / 1e6,
+            eta
+        );
+    })
+}
@@ -0,0 +1,155 @@
+//! Errors returned to the frontend, sorted into categories so it can tell
+//! e.g. a missing filter apart from a network error or a full disk
+
+use std::{fmt, path::PathBuf};
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+pub enum AppError {
+    /// A command was called before the state it needs was set
+    StateMissing {
+        what: String,
+        message: String,
+    },
+    /// The export XML couldn't be read; `line` and `column` are one indexed
+    XmlParse {
+        line: Option<u64>,
+        column: Option<u64>,
+        message: String,
+    },
+    /// An image couldn't be downloaded
+    Fetch {
+        url: Option<String>,
+        status: Option<u16>,
+        message: String,
+    },
+    /// Downloaded bytes weren't a valid image
+    Decode {
+        message: String,
+    },
+    /// Reading or writing a file failed; `io_kind` is e.g. `StorageFull`
+    Io {
+        path: Option<PathBuf>,
+        io_kind: String,
+        message: String,
+    },
+    Other {
+        message: String,
+    },
+}
+
+impl AppError {
+    pub fn missing(what: &str) -> Self {
+        Self::StateMissing {
+            what: what.to_string(),
+            message: format!("Missing {}", what),
+        }
+    }
+
+    pub fn other(message: impl Into<String>) -> Self {
+        Self::Other {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::StateMissing { message, .. }
+            | Self::XmlParse { message, .. }
+            | Self::Fetch { message, .. }
+            | Self::Decode { message }
+            | Self::Io { message, .. }
+            | Self::Other { message } => write!(f, "{}", message),
+        }
+    }
+}
+
+/// Context for errors reading or writing a file, so that its path can be
+/// reported, e.g. `.with_context(|| PathContext::new("creating output", &path))`
+#[derive(Debug)]
+pub struct PathContext {
+    action: &'static str,
+    path: PathBuf,
+}
+
+impl PathContext {
+    pub fn new(action: &'static str, path: impl Into<PathBuf>) -> Self {
+        Self {
+            action,
+            path: path.into(),
+        }
+    }
+}
+
+impl fmt::Display for PathContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} <{}>", self.action, self.path.display())
+    }
+}
+
+/// Context for errors in the meaning of the export XML, e.g. a missing tag, so
+/// that where the parser got to is reported like it is for malformed XML
+#[derive(Debug)]
+pub struct XmlPosition {
+    /// Zero indexed
+    line: u64,
+    column: u64,
+}
+
+impl XmlPosition {
+    pub fn new(line: u64, column: u64) -> Self {
+        Self { line, column }
+    }
+}
+
+impl fmt::Display for XmlPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at line {}, column {}", self.line + 1, self.column + 1)
+    }
+}
+
+impl From<anyhow::Error> for AppError {
+    fn from(err: anyhow::Error) -> Self {
+        let message = format!("{:#}", err);
+
+        // the innermost errors are the most specific, so look at those first
+        let chain: Vec<_> = err.chain().collect();
+        for cause in chain.iter().rev() {
+            if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+                return Self::Fetch {
+                    url: e.url().map(|u| u.to_string()),
+                    status: e.status().map(|s| s.as_u16()),
+                    message,
+                };
+            }
+            if cause.downcast_ref::<image::ImageError>().is_some() {
+                return Self::Decode { message };
+            }
+            if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+                return Self::Io {
+                    path: err.downcast_ref::<PathContext>().map(|c| c.path.clone()),
+                    io_kind: format!("{:?}", e.kind()),
+                    message,
+                };
+            }
+        }
+
+        if let Some(pos) = err.downcast_ref::<XmlPosition>() {
+            return Self::XmlParse {
+                line: Some(pos.line + 1),
+                column: Some(pos.column + 1),
+                message,
+            };
+        }
+
+        Self::Other { message }
+    }
+}
+
+impl From<tauri::Error> for AppError {
+    fn from(err: tauri::Error) -> Self {
+        Self::from(anyhow::Error::from(err))
+    }
+}
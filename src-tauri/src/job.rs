@@ -0,0 +1,126 @@
+//! Export jobs saved to disk, so that the same export can be rerun on every
+//! plate of a campaign, from the app or the command line
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use tauri::{async_runtime::Mutex, ipc::Channel, AppHandle, State};
+
+use crate::{
+    error::{AppError, PathContext},
+    layout::apply_layout,
+    parse_xml::{load_harmony, parse_in_background, Harmony, ParseEvent},
+    process::{ExistingFiles, ImageFilter, OutputInfo},
+    AppState,
+};
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct ExportJob {
+    /// Export XML file, or archive containing it
+    pub xml: PathBuf,
+    /// Plate layout CSV to import, e.g. for `filter.conditions`
+    #[serde(default)]
+    pub layout: Option<PathBuf>,
+    pub filter: ImageFilter,
+    pub output: OutputInfo,
+}
+
+impl ExportJob {
+    pub fn load(path: &Path) -> Result<Self> {
+        let f = File::open(path).with_context(|| PathContext::new("opening job", path))?;
+        serde_json::from_reader(BufReader::new(f))
+            .with_context(|| format!("reading job <{}>", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let f = File::create(path).with_context(|| PathContext::new("creating job", path))?;
+        serde_json::to_writer_pretty(BufWriter::new(f), self)
+            .with_context(|| format!("writing job <{}>", path.display()))
+    }
+
+    /// Read the export the job is run on, along with its plate layout
+    pub fn load_export(&self, cache_dir: Option<&Path>) -> Result<Harmony> {
+        let mut hm = load_harmony(&self.xml, cache_dir, |_, _| ())
+            .with_context(|| format!("reading <{}>", self.xml.display()))?;
+        self.apply_layout(&mut hm)?;
+        Ok(hm)
+    }
+
+    /// Set the job up to run again after it failed. Outputs the failed run
+    /// finished are still there, so they're skipped instead of failing the plan.
+    pub fn retrying(&mut self) {
+        if self.output.existing == ExistingFiles::Fail {
+            self.output.existing = ExistingFiles::Skip;
+        }
+    }
+
+    fn apply_layout(&self, hm: &mut Harmony) -> Result<()> {
+        match self.layout {
+            Some(ref layout) => apply_layout(hm, layout)
+                .with_context(|| format!("importing layout <{}>", layout.display())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl TryFrom<&AppState> for ExportJob {
+    type Error = AppError;
+
+    fn try_from(state: &AppState) -> std::result::Result<Self, AppError> {
+        let missing = AppError::missing;
+        let info = state
+            .info
+            .as_ref()
+            .ok_or_else(|| missing("measurement info"))?;
+        let filter = state
+            .filter
+            .as_ref()
+            .ok_or_else(|| missing("filter info"))?;
+        let output = state
+            .output
+            .as_ref()
+            .ok_or_else(|| missing("output info"))?;
+
+        Ok(Self {
+            xml: info.source.path().to_path_buf(),
+            layout: info.layout.clone(),
+            filter: filter.clone(),
+            output: output.clone(),
+        })
+    }
+}
+
+#[tauri::command]
+pub async fn save_job(path: PathBuf, state: State<'_, Mutex<AppState>>) -> Result<(), AppError> {
+    let state = state.lock().await;
+
+    let job = ExportJob::try_from(&*state)?;
+    Ok(job.save(&path)?)
+}
+
+#[tauri::command]
+pub async fn load_job(
+    path: PathBuf,
+    on_event: Channel<ParseEvent>,
+    app: AppHandle,
+    state: State<'_, Mutex<AppState>>,
+) -> Result<(), AppError> {
+    let job = ExportJob::load(&path)?;
+    let (mut info, report) = parse_in_background(job.xml.clone(), on_event, &app).await?;
+    job.apply_layout(&mut info)?;
+
+    let mut state = state.lock().await;
+    *state = AppState {
+        info: Some(Arc::new(info)),
+        report: Some(report),
+        filter: Some(job.filter),
+        output: Some(job.output),
+    };
+
+    Ok(())
+}
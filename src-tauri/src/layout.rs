@@ -0,0 +1,213 @@
+//! Plate layouts, describing what was put into each well. These are imported
+//! from a CSV file (e.g. an Excel export) with one row per well:
+//!
+//! ```text
+//! Well,Compound,Concentration,Unit,Condition,Replicate
+//! B03,DMSO,0.1,%,vehicle,1
+//! B04,Staurosporine,1,uM,treated,1
+//! ```
+//!
+//! Only the well column is required. It can also be given as separate
+//! `Row` and `Column` columns, with the row as a letter or a number.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use tauri::{async_runtime::Mutex, State};
+
+use crate::{error::AppError, parse_xml::Harmony, AppState};
+
+/// What was put into a well
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct WellLayout {
+    pub compound: Option<String>,
+    pub concentration: Option<f64>,
+    pub unit: Option<String>,
+    pub condition: Option<String>,
+    pub replicate: Option<u32>,
+}
+
+impl WellLayout {
+    /// Does the well match `label`, by either its condition or compound?
+    pub fn matches(&self, label: &str) -> bool {
+        let eq = |v: &Option<String>| v.as_deref().is_some_and(|v| v.eq_ignore_ascii_case(label));
+        eq(&self.condition) || eq(&self.compound)
+    }
+
+    /// Short description for file names, e.g. `Staurosporine_1uM`
+    pub fn label(&self) -> Option<String> {
+        let name = self.condition.as_ref().or(self.compound.as_ref())?;
+        let label = match (self.concentration, &self.unit) {
+            (Some(c), Some(u)) => format!("{}_{}{}", name, c, u),
+            (Some(c), None) => format!("{}_{}", name, c),
+            _ => name.clone(),
+        };
+
+        // keep the label safe to use in a file name
+        let label = label
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c,
+                _ => '_',
+            })
+            .collect();
+
+        Some(label)
+    }
+}
+
+pub type PlateLayout = HashMap<(u8, u8), WellLayout>;
+
+/// Column indices of the known headers
+struct Columns {
+    well: Option<usize>,
+    row: Option<usize>,
+    col: Option<usize>,
+    compound: Option<usize>,
+    concentration: Option<usize>,
+    unit: Option<usize>,
+    condition: Option<usize>,
+    replicate: Option<usize>,
+}
+
+impl Columns {
+    fn new(headers: &csv::StringRecord) -> Result<Self> {
+        let find = |names: &[&str]| {
+            headers.iter().position(|h| {
+                let h = h.trim();
+                names.iter().any(|n| h.eq_ignore_ascii_case(n))
+            })
+        };
+
+        let cols = Self {
+            well: find(&["Well", "WellName", "Well Name"]),
+            row: find(&["Row"]),
+            col: find(&["Col", "Column"]),
+            compound: find(&["Compound", "Treatment", "Drug"]),
+            concentration: find(&["Concentration", "Conc", "Dose"]),
+            unit: find(&["Unit", "Units"]),
+            condition: find(&["Condition", "Group"]),
+            replicate: find(&["Replicate", "Rep"]),
+        };
+
+        if cols.well.is_none() && (cols.row.is_none() || cols.col.is_none()) {
+            bail!("Layout needs a <Well> column, or <Row> and <Column> columns");
+        }
+
+        Ok(cols)
+    }
+
+    fn parse(&self, rec: &csv::StringRecord) -> Result<((u8, u8), WellLayout)> {
+        let get = |i: Option<usize>| {
+            i.and_then(|i| rec.get(i))
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+        };
+
+        let well = match self.well {
+            Some(_) => get(self.well)
+                .ok_or_else(|| anyhow!("Missing well"))
+                .and_then(parse_well)?,
+            None => {
+                let row = get(self.row).ok_or_else(|| anyhow!("Missing row"))?;
+                let col = get(self.col).ok_or_else(|| anyhow!("Missing column"))?;
+                (parse_row(row)?, col.parse().context("parsing column")?)
+            }
+        };
+
+        let layout = WellLayout {
+            compound: get(self.compound).map(String::from),
+            concentration: get(self.concentration)
+                .map(|c| c.parse().context("parsing concentration"))
+                .transpose()?,
+            unit: get(self.unit).map(String::from),
+            condition: get(self.condition).map(String::from),
+            replicate: get(self.replicate)
+                .map(|r| r.parse().context("parsing replicate"))
+                .transpose()?,
+        };
+
+        Ok((well, layout))
+    }
+}
+
+/// Row as a letter (`A` = 1, `AA` = 27) or as a one indexed number
+pub(crate) fn parse_row(s: &str) -> Result<u8> {
+    if let Ok(n) = s.parse::<u8>() {
+        return Ok(n);
+    }
+
+    s.chars()
+        .try_fold(0u32, |acc, c| match c.to_ascii_uppercase() {
+            l @ 'A'..='Z' => Ok(acc * 26 + (l as u32 - 'A' as u32 + 1)),
+            _ => Err(anyhow!("Invalid row <{}>", s)),
+        })
+        .and_then(|n| u8::try_from(n).with_context(|| format!("Row <{}> is too large", s)))
+}
+
+/// Well names like `B03`, `b3` or `AA12` into (row, col) [one indexed]
+fn parse_well(s: &str) -> Result<(u8, u8)> {
+    let split = s
+        .find(|c: char| c.is_ascii_digit())
+        .filter(|&i| i > 0)
+        .ok_or_else(|| anyhow!("Invalid well name <{}>", s))?;
+    let (row, col) = s.split_at(split);
+
+    let col = col
+        .parse::<u8>()
+        .with_context(|| format!("parsing column of well <{}>", s))?;
+
+    Ok((parse_row(row)?, col))
+}
+
+pub fn read_layout(path: &Path) -> Result<PlateLayout> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .with_context(|| format!("opening layout <{}>", path.display()))?;
+
+    let cols = Columns::new(rdr.headers().context("reading layout headers")?)?;
+
+    rdr.records()
+        .enumerate()
+        .map(|(i, rec)| {
+            rec.context("reading layout row")
+                .and_then(|rec| cols.parse(&rec))
+                // header is line 1
+                .with_context(|| format!("parsing layout line {}", i + 2))
+        })
+        .collect()
+}
+
+/// Attach the layout in `path` to the wells of `hm`, replacing any earlier one
+pub fn apply_layout(hm: &mut Harmony, path: &Path) -> Result<()> {
+    let layout = read_layout(path)?;
+
+    for (well, info) in hm.wells.iter_mut() {
+        info.layout = layout.get(well).cloned();
+    }
+    hm.layout = Some(path.to_path_buf());
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn import_layout(
+    path: PathBuf,
+    state: State<'_, Mutex<AppState>>,
+) -> Result<(), AppError> {
+    let mut state = state.lock().await;
+    let hm = state
+        .info
+        .as_mut()
+        .ok_or_else(|| AppError::missing("Harmony information"))?;
+    // a running download keeps its own copy with the old layout
+    let hm = Arc::make_mut(hm);
+
+    Ok(apply_layout(hm, &path)?)
+}
@@ -1,29 +1,50 @@
+use std::sync::Arc;
+
+use error::AppError;
 use parse_xml::{Harmony, XmlInfo};
 use process::{DownloadInfo, ImageFilter, OutputInfo};
 use tauri::{async_runtime::Mutex, Builder, Manager, State};
+use validate::ValidationReport;
 
+mod cache;
+pub mod cli;
+mod error;
+mod job;
+mod layout;
+mod logging;
+mod net;
 mod parse_xml;
 mod process;
+mod queue;
+mod source;
+mod validate;
+mod watch;
 
 #[derive(Default)]
 struct AppState {
-    info: Option<Harmony>,
+    /// Shared with a running download, so it doesn't need to hold the lock
+    info: Option<Arc<Harmony>>,
+    /// Problems found in `info` when it was parsed
+    report: Option<ValidationReport>,
     filter: Option<ImageFilter>,
     output: Option<OutputInfo>,
 }
 
 #[tauri::command]
-async fn get_info(state: State<'_, Mutex<AppState>>) -> Result<XmlInfo, String> {
+async fn get_info(state: State<'_, Mutex<AppState>>) -> Result<XmlInfo, AppError> {
     let state = state.lock().await;
 
-    match state.info {
-        Some(ref h) => Ok(XmlInfo::from(h)),
-        None => Err("App has not yet generated Harmony Information".into()),
+    match (&state.info, &state.report) {
+        (Some(h), Some(report)) => Ok(XmlInfo::from((&**h, report))),
+        _ => Err(AppError::missing("Harmony information")),
     }
 }
 
 #[tauri::command]
-async fn set_filter(filter: ImageFilter, state: State<'_, Mutex<AppState>>) -> Result<(), String> {
+async fn set_filter(
+    filter: ImageFilter,
+    state: State<'_, Mutex<AppState>>,
+) -> Result<(), AppError> {
     let mut state = state.lock().await;
     state.filter = Some(filter);
 
@@ -31,7 +52,7 @@ async fn set_filter(filter: ImageFilter, state: State<'_, Mutex<AppState>>) -> R
 }
 
 #[tauri::command]
-async fn set_output(info: OutputInfo, state: State<'_, Mutex<AppState>>) -> Result<(), String> {
+async fn set_output(info: OutputInfo, state: State<'_, Mutex<AppState>>) -> Result<(), AppError> {
     let mut state = state.lock().await;
     state.output = Some(info);
 
@@ -39,14 +60,14 @@ async fn set_output(info: OutputInfo, state: State<'_, Mutex<AppState>>) -> Resu
 }
 
 #[tauri::command]
-async fn get_dl_info(state: State<'_, Mutex<AppState>>) -> Result<DownloadInfo, String> {
+async fn get_dl_info(state: State<'_, Mutex<AppState>>) -> Result<DownloadInfo, AppError> {
     let state = state.lock().await;
 
-    DownloadInfo::try_from(&*state).map_err(|err| format!("{:?}", err))
+    DownloadInfo::try_from(&*state)
 }
 
 #[tauri::command]
-async fn reset_state(state: State<'_, Mutex<AppState>>) -> Result<(), String> {
+async fn reset_state(state: State<'_, Mutex<AppState>>) -> Result<(), AppError> {
     let mut state = state.lock().await;
     *state = AppState::default();
     Ok(())
@@ -63,11 +84,51 @@ pub fn run() {
             set_filter,
             set_output,
             reset_state,
+            layout::import_layout,
+            job::save_job,
+            job::load_job,
             parse_xml::parse_xml,
             process::start_download,
+            process::preview_conflicts,
+            process::plan_download,
+            process::get_job_status,
+            process::pause_download,
+            process::resume_download,
+            validate::validate_xml,
+            queue::enqueue_jobs,
+            queue::get_queue,
+            queue::remove_job,
+            queue::retry_job,
+            queue::run_queue,
+            net::get_net_settings,
+            net::set_net_settings,
+            net::set_http_password,
+            source::set_spool_dir,
         ])
         .setup(|app| {
+            logging::init(app.path().app_log_dir().ok().as_deref());
             app.manage(Mutex::new(AppState::default()));
+            app.manage(Mutex::new(process::JobRegistry::default()));
+
+            let net_file = app
+                .path()
+                .app_config_dir()
+                .ok()
+                .map(|d| d.join(net::SETTINGS_NAME));
+            let net_settings = net::NetSettings::load(net_file);
+            if let Err(e) = net::configure(&net_settings) {
+                tracing::error!(error = ?e, "couldn't apply network settings, fetches will fail");
+            }
+            app.manage(Mutex::new(net_settings));
+
+            if let Ok(dir) = app.path().app_data_dir() {
+                process::atomic::clean_up_interrupted(dir.join("running-exports.json"));
+            }
+
+            let queue_file = app.path().app_data_dir().ok().map(|d| d.join("queue.json"));
+            let queue = queue::JobQueue::load(queue_file.clone())
+                .unwrap_or_else(|e| queue::JobQueue::replace_broken(queue_file, &e));
+            app.manage(Mutex::new(queue));
             Ok(())
         })
         .run(tauri::generate_context!())
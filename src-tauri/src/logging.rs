@@ -0,0 +1,155 @@
+//! Logging setup. Everything goes to stderr and a rotating log in the app log
+//! dir, and events from inside an export also go to a log file in that
+//! export's output folder, so failed overnight runs can be looked into later.
+
+use std::{
+    fmt::{self, Write as _},
+    fs::File,
+    io::Write,
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id},
+    Event, Level, Subscriber,
+};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{
+    filter::Targets, layer::Context, prelude::*, registry::LookupSpan, Layer,
+};
+
+/// Name of the span that wraps an export. Its `run_log` field is the path of
+/// the log file for that run.
+pub const RUN_SPAN: &str = "export";
+/// File name of the per run log, written into the output folder
+pub const RUN_LOG_NAME: &str = "harmony-dl-run.log";
+
+/// Set up logging, with the rotating app log written into `log_dir` if given
+pub fn init(log_dir: Option<&Path>) {
+    let app_log = log_dir.and_then(|dir| {
+        RollingFileAppender::builder()
+            .rotation(Rotation::DAILY)
+            .filename_prefix("harmony-dl")
+            .filename_suffix("log")
+            .max_log_files(14)
+            .build(dir)
+            .ok()
+    });
+
+    let filter = Targets::new()
+        .with_target("harmony_dl_lib", Level::DEBUG)
+        .with_default(Level::WARN);
+
+    let _ = tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .with(app_log.map(|f| {
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(f)
+        }))
+        .with(RunLogLayer)
+        .try_init();
+}
+
+/// Log file of one export, stored on its span
+struct RunLog(Mutex<File>);
+
+/// Writes events from inside an export span into the log file of that run
+struct RunLogLayer;
+
+impl<S> Layer<S> for RunLogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if attrs.metadata().name() != RUN_SPAN {
+            return;
+        }
+
+        let mut path = FindField::new("run_log");
+        attrs.record(&mut path);
+        let file = path
+            .value
+            .and_then(|p| File::options().create(true).append(true).open(p).ok());
+
+        if let (Some(f), Some(span)) = (file, ctx.span(id)) {
+            span.extensions_mut().insert(RunLog(Mutex::new(f)));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(scope) = ctx.event_scope(event) else {
+            return;
+        };
+
+        for span in scope {
+            let ext = span.extensions();
+            let Some(log) = ext.get::<RunLog>() else {
+                continue;
+            };
+
+            let mut line = FieldText::default();
+            event.record(&mut line);
+
+            let time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let meta = event.metadata();
+            if let Ok(mut f) = log.0.lock() {
+                let _ = writeln!(
+                    f,
+                    "{}.{:03} {:>5} {}:{}",
+                    time.as_secs(),
+                    time.subsec_millis(),
+                    meta.level(),
+                    meta.target(),
+                    line.0
+                );
+            }
+            return;
+        }
+    }
+}
+
+/// Formats the fields of an event as `message key=value ...`
+#[derive(Default)]
+struct FieldText(String);
+
+impl Visit for FieldText {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let _ = match field.name() {
+            "message" => write!(self.0, " {:?}", value),
+            name => write!(self.0, " {}={:?}", name, value),
+        };
+    }
+}
+
+/// Picks out the value of one field
+struct FindField {
+    name: &'static str,
+    value: Option<String>,
+}
+
+impl FindField {
+    fn new(name: &'static str) -> Self {
+        Self { name, value: None }
+    }
+}
+
+impl Visit for FindField {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == self.name {
+            self.value = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == self.name {
+            self.value = Some(format!("{:?}", value));
+        }
+    }
+}
@@ -2,5 +2,10 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 fn main() {
+    // run headless when given a command, otherwise open the app
+    if let Some(code) = harmony_dl_lib::cli::run_from_args() {
+        std::process::exit(code);
+    }
+
     harmony_dl_lib::run()
 }
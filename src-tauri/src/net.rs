@@ -0,0 +1,426 @@
+//! Network settings shared by every download. All image fetches go through the
+//! same throttle and HTTP client, so the bandwidth limit holds across parallel
+//! exports, e.g. when the queue runs several jobs at once, and connections to
+//! the Harmony server are reused.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex as StdMutex, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::Timelike;
+use reqwest::blocking::{Client, Response};
+use tauri::{async_runtime::Mutex, State};
+
+use crate::error::{AppError, PathContext};
+
+/// How much bandwidth image downloads may use
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct BandwidthLimit {
+    /// Cap outside of quiet hours, unlimited if not set
+    pub bytes_per_sec: Option<u64>,
+    pub quiet_hours: Option<QuietHours>,
+}
+
+/// Hours of the day, in local time, when downloads should go easy on the
+/// network, e.g. while the instrument is in use
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct QuietHours {
+    /// First hour, 0-23
+    pub start: u32,
+    /// Hour they end at; this can be before `start` to wrap around midnight
+    pub end: u32,
+    /// Cap during quiet hours, with 0 stopping downloads until they end
+    pub bytes_per_sec: u64,
+}
+
+impl QuietHours {
+    fn contains(&self, hour: u32) -> bool {
+        match self.start <= self.end {
+            true => (self.start..self.end).contains(&hour),
+            false => hour >= self.start || hour < self.end,
+        }
+    }
+}
+
+impl BandwidthLimit {
+    fn check(&self) -> Result<()> {
+        if let Some(q) = self.quiet_hours {
+            if q.start > 23 || q.end > 23 {
+                bail!(
+                    "Quiet hours have to be between 0 and 23, not {}-{}",
+                    q.start,
+                    q.end
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes per second allowed at `hour`, `None` being unlimited
+    fn at(&self, hour: u32) -> Option<u64> {
+        match self.quiet_hours {
+            Some(q) if q.contains(hour) => Some(q.bytes_per_sec),
+            _ => self.bytes_per_sec,
+        }
+    }
+}
+
+/// Name passwords are stored under in the OS keyring
+const KEYRING_SERVICE: &str = "harmony-dl";
+
+/// Name of the settings file in the app config dir
+pub const SETTINGS_NAME: &str = "network.json";
+
+/// The settings file the app saves to, for running without it. Tauri puts the
+/// config dir under the `identifier` from `tauri.conf.json`.
+pub fn app_settings_file() -> Option<PathBuf> {
+    dirs::config_dir().map(|d| d.join("com.harmony-dl.app").join(SETTINGS_NAME))
+}
+
+fn store_password(username: &str, password: &str) -> Result<()> {
+    keyring::Entry::new(KEYRING_SERVICE, username)
+        .and_then(|entry| entry.set_password(password))
+        .with_context(|| format!("storing password for <{}> in the keyring", username))
+}
+
+/// How to connect to the Harmony server
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct HttpSettings {
+    /// User for basic auth
+    pub username: Option<String>,
+    /// Password for basic auth. If it isn't set, it's looked up in the OS
+    /// keyring, which is where the app puts it; it's never written out.
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+    /// e.g. `http://proxy.example.org:3128`
+    pub proxy: Option<String>,
+    /// PEM files of extra root certificates to trust, e.g. an institutional CA
+    #[serde(default)]
+    pub root_certs: Vec<PathBuf>,
+    pub user_agent: Option<String>,
+    /// Most idle connections to keep open to a server
+    pub max_idle_connections: Option<usize>,
+}
+
+impl HttpSettings {
+    fn client(&self) -> Result<Client> {
+        let user_agent = self
+            .user_agent
+            .clone()
+            .unwrap_or_else(|| format!("harmony-dl/{}", env!("CARGO_PKG_VERSION")));
+        let mut builder = Client::builder().user_agent(user_agent).cookie_store(true);
+
+        if let Some(ref proxy) = self.proxy {
+            let proxy =
+                reqwest::Proxy::all(proxy).with_context(|| format!("setting proxy <{}>", proxy))?;
+            builder = builder.proxy(proxy);
+        }
+        for path in &self.root_certs {
+            let pem =
+                fs::read(path).with_context(|| PathContext::new("reading certificate", path))?;
+            let cert = reqwest::Certificate::from_pem(&pem)
+                .with_context(|| format!("reading certificate <{}>", path.display()))?;
+            builder = builder.add_root_certificate(cert);
+        }
+        if let Some(n) = self.max_idle_connections {
+            builder = builder.pool_max_idle_per_host(n);
+        }
+
+        builder.build().context("creating HTTP client")
+    }
+
+    /// Username and password for basic auth, if a user is set
+    fn credentials(&self) -> Result<Option<(String, String)>> {
+        let Some(ref user) = self.username else {
+            return Ok(None);
+        };
+        let password = match self.password {
+            Some(ref pw) => pw.clone(),
+            None => keyring::Entry::new(KEYRING_SERVICE, user)
+                .and_then(|entry| entry.get_password())
+                .with_context(|| format!("getting password for <{}> from the keyring", user))?,
+        };
+        Ok(Some((user.clone(), password)))
+    }
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct NetSettings {
+    #[serde(default)]
+    pub bandwidth: BandwidthLimit,
+    #[serde(default)]
+    pub http: HttpSettings,
+    // where the settings are saved
+    #[serde(skip)]
+    file: Option<PathBuf>,
+}
+
+impl NetSettings {
+    /// Read the settings saved in `file`, using the defaults if there aren't any
+    pub fn load(file: Option<PathBuf>) -> Self {
+        let saved: Option<Self> = file
+            .as_ref()
+            .and_then(|f| File::open(f).ok())
+            .and_then(|f| serde_json::from_reader(BufReader::new(f)).ok());
+
+        let mut settings = saved.unwrap_or_default();
+        settings.file = file;
+        settings
+    }
+
+    /// Read settings from `path`, failing if they can't be read
+    pub fn read(path: &Path) -> Result<Self> {
+        let f = File::open(path).with_context(|| PathContext::new("opening settings", path))?;
+        serde_json::from_reader(BufReader::new(f))
+            .with_context(|| format!("reading settings <{}>", path.display()))
+    }
+
+    fn save(&self) -> Result<()> {
+        let Some(ref path) = self.file else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).context("creating app config dir")?;
+        }
+
+        let f = File::create(path).with_context(|| PathContext::new("creating settings", path))?;
+        serde_json::to_writer_pretty(BufWriter::new(f), self).context("writing settings")
+    }
+}
+
+/// Spaces out fetches so that, on average, they stay under the bandwidth limit
+struct Throttle {
+    limit: BandwidthLimit,
+    // when the bytes fetched so far would have finished at the allowed rate
+    next_free: StdMutex<Instant>,
+}
+
+impl Throttle {
+    /// How often to check whether quiet hours that stop downloads have ended
+    const QUIET_CHECK: Duration = Duration::from_secs(60);
+
+    fn new(limit: BandwidthLimit) -> Self {
+        Self {
+            limit,
+            next_free: StdMutex::new(Instant::now()),
+        }
+    }
+
+    fn current(&self) -> Option<u64> {
+        self.limit.at(chrono::Local::now().hour())
+    }
+
+    /// Whether downloads are stopped for quiet hours
+    fn stopped(&self) -> bool {
+        self.current() == Some(0)
+    }
+
+    /// Account for `bytes` having been fetched, sleeping for as long as it
+    /// should have taken at the allowed rate
+    fn fetched(&self, bytes: u64) {
+        let Some(rate) = self.current().filter(|&r| r > 0) else {
+            return;
+        };
+
+        let wait = {
+            let mut next = self.next_free.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            *next = (*next).max(now) + Duration::from_secs_f64(bytes as f64 / rate as f64);
+            *next - now
+        };
+        thread::sleep(wait);
+    }
+}
+
+/// What every fetch shares
+struct Network {
+    throttle: Throttle,
+    client: Client,
+    credentials: Option<(String, String)>,
+}
+
+impl Network {
+    fn new(settings: &NetSettings) -> Result<Self> {
+        settings.bandwidth.check()?;
+
+        Ok(Self {
+            throttle: Throttle::new(settings.bandwidth.clone()),
+            client: settings.http.client()?,
+            credentials: settings.http.credentials()?,
+        })
+    }
+}
+
+/// What fetches use, or why the settings couldn't be applied
+static NETWORK: RwLock<Option<Result<Arc<Network>, String>>> = RwLock::new(None);
+
+fn set_network(network: Result<Arc<Network>, String>) {
+    *NETWORK.write().unwrap_or_else(|e| e.into_inner()) = Some(network);
+}
+
+/// Apply `settings` to every fetch from now on. If they can't be applied,
+/// fetches fail with the same error until they're configured again.
+pub fn configure(settings: &NetSettings) -> Result<()> {
+    match Network::new(settings) {
+        Ok(network) => {
+            set_network(Ok(Arc::new(network)));
+            log_settings(settings);
+            Ok(())
+        }
+        Err(e) => {
+            set_network(Err(format!("{:#}", e)));
+            Err(e)
+        }
+    }
+}
+
+/// Apply `settings` if they work, keeping the current ones otherwise
+fn reconfigure(settings: &NetSettings) -> Result<()> {
+    set_network(Ok(Arc::new(Network::new(settings)?)));
+    log_settings(settings);
+    Ok(())
+}
+
+fn log_settings(settings: &NetSettings) {
+    let http = &settings.http;
+    tracing::info!(
+        bandwidth = ?settings.bandwidth,
+        user = ?http.username,
+        proxy = ?http.proxy,
+        root_certs = http.root_certs.len(),
+        "configured network"
+    );
+}
+
+/// The configured network, or the defaults if `configure` wasn't called
+fn network() -> Result<Arc<Network>> {
+    match *NETWORK.read().unwrap_or_else(|e| e.into_inner()) {
+        Some(Ok(ref network)) => return Ok(network.clone()),
+        Some(Err(ref e)) => bail!("Network settings couldn't be applied: {}", e),
+        None => (),
+    }
+
+    let mut network = NETWORK.write().unwrap_or_else(|e| e.into_inner());
+    match *network {
+        Some(Ok(ref n)) => Ok(n.clone()),
+        Some(Err(ref e)) => bail!("Network settings couldn't be applied: {}", e),
+        None => {
+            let n = Arc::new(Network::new(&NetSettings::default())?);
+            *network = Some(Ok(n.clone()));
+            Ok(n)
+        }
+    }
+}
+
+/// Keeps the reads of one fetch to the bandwidth limit
+pub struct Limiter(Arc<Network>);
+
+impl Limiter {
+    /// Size of the chunks bodies are read in, so large images don't go over
+    /// the limit while they're being read
+    const CHUNK: usize = 64 * 1024;
+
+    /// Read all of `rdr`, sleeping after each chunk for as long as it should
+    /// have taken at the allowed rate
+    pub fn read_to_end(&self, mut rdr: impl Read, size_hint: u64) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(size_hint as usize);
+        let mut chunk = vec![0; Self::CHUNK];
+
+        loop {
+            let n = match rdr.read(&mut chunk) {
+                Ok(0) => return Ok(buf),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            buf.extend_from_slice(&chunk[..n]);
+            self.0.throttle.fetched(n as u64);
+        }
+    }
+}
+
+/// Run `fetch` once downloads aren't stopped for quiet hours, reading the
+/// image through the limiter it's given
+pub fn throttled<T>(fetch: impl FnOnce(&Limiter) -> Result<T>) -> Result<T> {
+    // look at the settings each time, in case they were changed while waiting
+    let network = loop {
+        let network = network()?;
+        if !network.throttle.stopped() {
+            break network;
+        }
+        thread::sleep(Throttle::QUIET_CHECK);
+    };
+
+    fetch(&Limiter(network))
+}
+
+/// GET `url` with the shared client
+pub fn get(url: &str) -> Result<Response> {
+    let network = network()?;
+
+    let mut req = network.client.get(url);
+    if let Some((ref user, ref password)) = network.credentials {
+        req = req.basic_auth(user, Some(password));
+    }
+    req.send()
+        .with_context(|| format!("downloading image from <{}>", url))
+}
+
+#[tauri::command]
+pub async fn get_net_settings(
+    settings: State<'_, Mutex<NetSettings>>,
+) -> Result<NetSettings, AppError> {
+    Ok(settings.lock().await.clone())
+}
+
+/// Save new settings, which apply to the next image fetched. A password in
+/// them goes into the OS keyring instead of the settings file.
+#[tauri::command]
+pub async fn set_net_settings(
+    mut new: NetSettings,
+    settings: State<'_, Mutex<NetSettings>>,
+) -> Result<(), AppError> {
+    let mut settings = settings.lock().await;
+
+    let password = new.http.password.take();
+    let checked = new.clone();
+    // the blocking HTTP client can't be built, or the old one dropped, on the
+    // async runtime
+    tauri::async_runtime::spawn_blocking(move || {
+        if let (Some(user), Some(pw)) = (&checked.http.username, password) {
+            store_password(user, &pw)?;
+        }
+        // check that the new settings work before saving them
+        reconfigure(&checked)
+    })
+    .await??;
+
+    settings.bandwidth = new.bandwidth;
+    settings.http = new.http;
+    settings.save()?;
+    Ok(())
+}
+
+/// Store the basic auth password of `username` in the OS keyring, so that it
+/// doesn't end up in the settings file
+#[tauri::command]
+pub async fn set_http_password(
+    username: String,
+    password: String,
+    settings: State<'_, Mutex<NetSettings>>,
+) -> Result<(), AppError> {
+    // pick up the new password
+    let settings = settings.lock().await.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        store_password(&username, &password)?;
+        configure(&settings)
+    })
+    .await??;
+    Ok(())
+}
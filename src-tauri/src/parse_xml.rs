@@ -1,18 +1,28 @@
-use super::AppState;
+use super::{
+    cache,
+    error::{AppError, XmlPosition},
+    layout::WellLayout,
+    source::ExportSource,
+    validate::ValidationReport,
+    AppState,
+};
 use anyhow::{anyhow, bail, Context, Result};
+use quick_xml::{events::Event, name::ResolveResult, NsReader};
 use std::{
     collections::{HashMap, HashSet},
-    fs::File,
-    io::{BufReader, Read},
-    path::Path,
+    fmt,
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    sync::Arc,
 };
-use tauri::{async_runtime::Mutex, State};
+use tauri::{async_runtime::Mutex, ipc::Channel as IpcChannel, AppHandle, Manager, State};
 
 // Helpers
 type TempMap = HashMap<String, String>;
 pub type ChanMap = HashMap<ChannelID, Channel>;
 type PlateVec<T> = Vec<Vec<Option<T>>>;
 type PlateMap<T> = HashMap<(u8, u8), T>;
+type XmlReader<R> = NsReader<LineCounter<BufReader<R>>>;
 
 fn get_from<'a>(map: &'a TempMap, key: &str) -> Result<&'a String> {
     map.get(key).ok_or_else(|| anyhow!("Missing key <{}>", key))
@@ -22,18 +32,10 @@ fn get_string<'a>(map: &'a TempMap, key: &str) -> Result<String> {
     get_from(map, key).map(String::to_string)
 }
 
-fn get_u8<'a>(map: &'a TempMap, key: &str) -> Result<u8> {
-    get_from(map, key).and_then(|s| s.parse::<u8>().context("parsing as u8"))
-}
-
 fn get_u16<'a>(map: &'a TempMap, key: &str) -> Result<u16> {
     get_from(map, key).and_then(|s| s.parse::<u16>().context("parsing as u16"))
 }
 
-fn get_u32<'a>(map: &'a TempMap, key: &str) -> Result<u32> {
-    get_from(map, key).and_then(|s| s.parse::<u32>().context("parsing as u32"))
-}
-
 fn get_f64<'a>(map: &'a TempMap, key: &str) -> Result<f64> {
     get_from(map, key).and_then(|s| s.parse::<f64>().context("parsing as f64"))
 }
@@ -44,64 +46,111 @@ fn get_f64<'a>(map: &'a TempMap, key: &str) -> Result<f64> {
 )]
 pub struct ChannelID(u8);
 
+impl From<ChannelID> for u32 {
+    fn from(id: ChannelID) -> Self {
+        id.0 as u32
+    }
+}
+
 /// Holds all of the necessary information from
 /// the harmony export XML file
 /// I can't figure out how multiple plates work, so
 /// I've reduced it to only having one plate...
-#[derive(Debug)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Harmony {
+    pub version: IndexVersion,
+    pub source: ExportSource,
     pub plate: Plate,
     pub channels: ChanMap,
     pub images: Vec<Image>,
     pub wells: PlateMap<WellInfo>,
+    /// Plate layout file imported into `wells`, if any
+    pub layout: Option<PathBuf>,
     pub timepoints: u16,
     pub fields_per_well: u16,
     pub planes_per_field: u16,
 }
 
 impl Harmony {
+    /// Plate layout of a well, if one was imported
+    pub fn well_layout(&self, row: u16, col: u16) -> Option<&WellLayout> {
+        self.wells
+            .get(&(row as u8, col as u8))
+            .and_then(|w| w.layout.as_ref())
+    }
+
+    /// Parse an export XML file, or an archive containing one, calling `progress`
+    /// with the number of bytes read so far and the total file size.
+    fn from_xml_path(p: &Path, progress: impl FnMut(u64, u64)) -> Result<Self> {
+        ExportSource::open(p, |source, rdr, total| {
+            Self::from_reader(ProgressReader::new(rdr, total, progress), source)
+        })
+    }
+
     // TODO: async read from tokio...? as stand alone function probably...
-    fn from_xml_path(p: &Path) -> Result<Self> {
-        use xml::reader::XmlEvent::{EndDocument, StartElement};
+    fn from_reader<R: Read>(rdr: R, source: ExportSource) -> Result<Self> {
+        IndexVersion::check_file(source.index_name())?;
 
-        let f = File::open(p).context("opening XML file")?;
-        let mut rdr = xml::ParserConfig::new()
-            .trim_whitespace(true)
-            .ignore_comments(true)
-            .cdata_to_characters(true)
-            .create_reader(BufReader::new(f));
+        let mut rdr =
+            NsReader::from_reader(LineCounter::new(BufReader::with_capacity(1 << 16, rdr)));
+        rdr.config_mut().trim_text(true);
+        // `<Tag/>` is read as an opening and a closing tag
+        rdr.config_mut().expand_empty_elements = true;
+        let mut buf = Vec::new();
 
+        let mut version = None;
         let mut plate = None;
         let mut channels = None;
         let mut images = None;
         let mut wells = None;
 
         loop {
-            let evt = rdr.next().context("getting next XML event")?;
+            let evt = next_event(&mut rdr, &mut buf)
+                .with_context(|| rdr.get_ref().position())
+                .context("getting next XML event")?;
 
             match evt {
-                StartElement { name, .. } if name.local_name == "Plates" => {
-                    plate = parse_plates(&mut rdr)
+                // the first element is the root, which tells us how to read the rest
+                Event::Start(e) if version.is_none() => {
+                    let (ns, name) = rdr.resolve_element(e.name());
+                    let ns = match ns {
+                        ResolveResult::Bound(ns) => Some(ns.0),
+                        _ => None,
+                    };
+                    version = IndexVersion::detect(name.as_ref(), ns)
+                        .map(Some)
+                        .with_context(|| rdr.get_ref().position())
+                        .context("detecting index file version")?;
+                }
+                Event::Start(e) if e.local_name().as_ref() == b"Plates" => {
+                    let version = version.context("missing root element")?;
+                    plate = parse_plates(&mut rdr, version)
                         .map(Some)
+                        .with_context(|| rdr.get_ref().position())
                         .context("parsing <Plates>")?;
                 }
-                StartElement { name, .. } if name.local_name == "Maps" => {
-                    channels = parse_maps(&mut rdr)
+                Event::Start(e) if e.local_name().as_ref() == b"Maps" => {
+                    let version = version.context("missing root element")?;
+                    channels = parse_maps(&mut rdr, version)
                         .map(Some)
+                        .with_context(|| rdr.get_ref().position())
                         .context("parsing channel info from <Maps>")?;
                 }
-                StartElement { name, .. } if name.local_name == "Images" => {
-                    images = parse_images(&mut rdr)
+                Event::Start(e) if e.local_name().as_ref() == b"Images" => {
+                    let version = version.context("missing root element")?;
+                    images = parse_images(&mut rdr, version)
                         .map(Some)
+                        .with_context(|| rdr.get_ref().position())
                         .context("parsing <Images>")?;
                     wells = images.as_deref().map(summarize_images);
                 }
-                EndDocument => break,
+                Event::Eof => break,
                 _ => (),
             }
         }
 
         // there has to be better way to do this..? map_n? match?
+        let version = version.ok_or_else(|| anyhow!("Empty XML file"))?;
         plate
             .zip(channels)
             .zip(images)
@@ -109,10 +158,13 @@ impl Harmony {
             .map(|(((plate, channels), images), wells)| {
                 let (f, p, tp) = summarize_wells(&wells);
                 Self {
+                    version,
+                    source,
                     plate,
                     channels,
                     images,
                     wells,
+                    layout: None,
                     fields_per_well: f,
                     planes_per_field: p,
                     timepoints: tp,
@@ -122,8 +174,269 @@ impl Harmony {
     }
 }
 
+/// Layout of the index file, detected from the namespace of the root element,
+/// e.g. `<EvaluationInputData xmlns="http://www.perkinelmer.com/PEHH/HarmonyV5">`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum IndexVersion {
+    /// Harmony 3.x/4.x and Columbus exports, which don't record `AbsPositionZ`
+    /// or the size of images
+    Legacy(u8),
+    /// Harmony 5+ and Opera Phenix/Operetta CLS `Index.idx.xml`
+    Current(u8),
+}
+
+/// Names of the elements each version of the index file keeps values in
+struct IndexTags {
+    plate_id: &'static str,
+    plate_name: &'static str,
+    plate_type: &'static str,
+    plate_rows: &'static str,
+    plate_cols: &'static str,
+    channel_name: &'static str,
+    resolution: (&'static str, &'static str),
+    magnification: &'static str,
+    // width and height in pixels, if the version records them
+    image_size: Option<(&'static str, &'static str)>,
+    // brightest value a pixel can have, which gives the bit depth
+    max_intensity: &'static str,
+    image: &'static [(&'static str, ImageTag)],
+}
+
+const LEGACY_TAGS: IndexTags = IndexTags {
+    plate_id: "PlateID",
+    plate_name: "Name",
+    plate_type: "PlateTypeName",
+    plate_rows: "PlateRows",
+    plate_cols: "PlateColumns",
+    channel_name: "ChannelName",
+    resolution: ("ImageResolutionX", "ImageResolutionY"),
+    magnification: "ObjectiveMagnification",
+    image_size: None,
+    max_intensity: "MaxIntensity",
+    image: &[
+        ("Row", ImageTag::Row),
+        ("Col", ImageTag::Col),
+        ("FieldID", ImageTag::Field),
+        ("PlaneID", ImageTag::Plane),
+        ("TimepointID", ImageTag::Timepoint),
+        ("ChannelID", ImageTag::Channel),
+        ("URL", ImageTag::Url),
+        ("PositionX", ImageTag::PositionX),
+        ("PositionY", ImageTag::PositionY),
+        ("PositionZ", ImageTag::PositionZ),
+    ],
+};
+
+const CURRENT_TAGS: IndexTags = IndexTags {
+    image_size: Some(("ImageSizeX", "ImageSizeY")),
+    image: &[
+        ("Row", ImageTag::Row),
+        ("Col", ImageTag::Col),
+        ("FieldID", ImageTag::Field),
+        ("PlaneID", ImageTag::Plane),
+        ("TimepointID", ImageTag::Timepoint),
+        ("ChannelID", ImageTag::Channel),
+        ("URL", ImageTag::Url),
+        ("PositionX", ImageTag::PositionX),
+        ("PositionY", ImageTag::PositionY),
+        ("PositionZ", ImageTag::PositionZ),
+        ("AbsPositionZ", ImageTag::AbsPositionZ),
+    ],
+    ..LEGACY_TAGS
+};
+
+impl IndexVersion {
+    const ROOT: &'static str = "EvaluationInputData";
+    const NAMESPACE: &'static str = "http://www.perkinelmer.com/PEHH/HarmonyV";
+
+    /// Index files that list their images in a way that can't be read, with
+    /// what to do instead
+    const UNSUPPORTED: [(&'static str, &'static str); 2] = [
+        (
+            "Index.ref.xml",
+            "Opera Phenix reference indexes point at images in other measurement folders; \
+            export the measurement from Harmony to get an Index.idx.xml",
+        ),
+        (
+            "Index.flex.xml",
+            "Opera/Evotec .flex indexes need to be re-exported from Columbus or Harmony",
+        ),
+    ];
+
+    /// Fail for index files that are known not to be readable, by file name
+    fn check_file(name: &str) -> Result<()> {
+        match Self::UNSUPPORTED
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        {
+            Some((n, hint)) => bail!("Unsupported index file <{}>: {}", n, hint),
+            None => Ok(()),
+        }
+    }
+
+    /// Version from the local name and namespace of the root element
+    fn detect(name: &[u8], namespace: Option<&[u8]>) -> Result<Self> {
+        if name != Self::ROOT.as_bytes() {
+            bail!(
+                "Unsupported index file with root element <{}>, expected <{}>. \
+                Opera/Evotec .flex indexes need to be re-exported from Columbus or Harmony",
+                String::from_utf8_lossy(name),
+                Self::ROOT
+            );
+        }
+
+        let ns = namespace
+            .map(String::from_utf8_lossy)
+            .ok_or_else(|| anyhow!("Missing namespace on <{}>", Self::ROOT))?;
+        let v = ns
+            .strip_prefix(Self::NAMESPACE)
+            .and_then(|v| v.parse::<u8>().ok())
+            .ok_or_else(|| anyhow!("Unrecognized index namespace <{}>", ns))?;
+
+        match v {
+            3..=4 => Ok(Self::Legacy(v)),
+            5..=7 => Ok(Self::Current(v)),
+            _ => bail!("Unsupported Harmony index version V{}", v),
+        }
+    }
+
+    fn tags(self) -> &'static IndexTags {
+        match self {
+            Self::Legacy(_) => &LEGACY_TAGS,
+            Self::Current(_) => &CURRENT_TAGS,
+        }
+    }
+
+    /// Map an `<Image>` child element name onto the field it fills
+    fn image_tag(self, name: &[u8]) -> Option<ImageTag> {
+        self.tags()
+            .image
+            .iter()
+            .find(|(n, _)| n.as_bytes() == name)
+            .map(|&(_, tag)| tag)
+    }
+}
+
+impl fmt::Display for IndexVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Legacy(v) | Self::Current(v) => write!(f, "Harmony V{}", v),
+        }
+    }
+}
+
+/// Wraps the XML source to report how far into the file the parser is.
+/// The callback only fires every `step` bytes so large files don't flood the UI.
+struct ProgressReader<R, F> {
+    inner: R,
+    read: u64,
+    total: u64,
+    next_report: u64,
+    step: u64,
+    progress: F,
+}
+
+impl<R: Read, F: FnMut(u64, u64)> ProgressReader<R, F> {
+    // report at most ~200 times, but not more often than every 4 MiB
+    const MIN_STEP: u64 = 4 << 20;
+
+    fn new(inner: R, total: u64, progress: F) -> Self {
+        let step = (total / 200).max(Self::MIN_STEP);
+        Self {
+            inner,
+            read: 0,
+            total,
+            next_report: step,
+            step,
+            progress,
+        }
+    }
+}
+
+impl<R: Read, F: FnMut(u64, u64)> Read for ProgressReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+
+        // always report once when the end of the file is reached
+        let at_end = n == 0 && self.next_report != u64::MAX;
+        if self.read >= self.next_report || at_end {
+            (self.progress)(self.read, self.total.max(self.read));
+            self.next_report = if n == 0 {
+                u64::MAX
+            } else {
+                self.read + self.step
+            };
+        }
+
+        Ok(n)
+    }
+}
+
+/// Counts lines in what the XML parser has consumed, so errors can say where
+/// in the file they are
+struct LineCounter<R> {
+    inner: R,
+    pos: TextPos,
+}
+
+/// Zero indexed line and column
+#[derive(Default)]
+struct TextPos {
+    line: u64,
+    column: u64,
+}
+
+impl TextPos {
+    fn advance(&mut self, bytes: &[u8]) {
+        match bytes.iter().rposition(|&b| b == b'\n') {
+            Some(last) => {
+                self.line += bytes.iter().filter(|&&b| b == b'\n').count() as u64;
+                self.column = (bytes.len() - last - 1) as u64;
+            }
+            None => self.column += bytes.len() as u64,
+        }
+    }
+}
+
+impl<R> LineCounter<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            pos: TextPos::default(),
+        }
+    }
+
+    fn position(&self) -> XmlPosition {
+        XmlPosition::new(self.pos.line, self.pos.column)
+    }
+}
+
+impl<R: BufRead> Read for LineCounter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.pos.advance(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for LineCounter<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        // the bytes being consumed are still at the start of the buffer, so
+        // this doesn't read anything
+        if let Ok(buf) = self.inner.fill_buf() {
+            self.pos.advance(&buf[..amt.min(buf.len())]);
+        }
+        self.inner.consume(amt);
+    }
+}
+
 /// Imaging plate information
-#[derive(Debug)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Plate {
     pub id: String,
     pub name: String,
@@ -132,38 +445,49 @@ pub struct Plate {
     pub cols: u16,
 }
 
-impl TryFrom<TempMap> for Plate {
+impl TryFrom<(TempMap, IndexVersion)> for Plate {
     type Error = anyhow::Error;
 
-    fn try_from(value: TempMap) -> Result<Self> {
+    fn try_from(value: (TempMap, IndexVersion)) -> Result<Self> {
+        let (value, version) = value;
+        let tags = version.tags();
+
         let get_str = |key| get_string(&value, key).context("parsing Plate");
         let get_u16 = |key| get_u16(&value, key).context("parsing Plate");
 
         Ok(Self {
-            id: get_str("PlateID")?,
-            name: get_str("Name")?,
-            kind: get_str("PlateTypeName")?,
-            rows: get_u16("PlateRows")?,
-            cols: get_u16("PlateColumns")?,
+            id: get_str(tags.plate_id)?,
+            name: get_str(tags.plate_name)?,
+            kind: get_str(tags.plate_type)?,
+            rows: get_u16(tags.plate_rows)?,
+            cols: get_u16(tags.plate_cols)?,
         })
     }
 }
 
 // TODO: FlatField correction info in <FlatfieldProfile>
-#[derive(Debug, serde::Serialize, Clone)]
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
 pub struct Channel {
     pub id: ChannelID,
     pub name: String,
     pub res: (f64, f64), // in microns
     pub mag: u16,
+    /// Image width and height in pixels, if the export records them
+    pub size: Option<(u32, u32)>,
+    /// Brightest value a pixel can have, e.g. 4095 for 12 bit images, if the
+    /// export records it
+    pub max_intensity: Option<u32>,
     //pub flatfield_profile: Vec<u8>,
 }
 
-impl TryFrom<(TempMap, ChannelID)> for Channel {
+impl TryFrom<(TempMap, ChannelID, IndexVersion)> for Channel {
     type Error = anyhow::Error;
 
-    fn try_from(value: (TempMap, ChannelID)) -> std::result::Result<Self, Self::Error> {
-        let (value, id) = value;
+    fn try_from(
+        value: (TempMap, ChannelID, IndexVersion),
+    ) -> std::result::Result<Self, Self::Error> {
+        let (value, id, version) = value;
+        let tags = version.tags();
 
         let get_str =
             |key| get_string(&value, key).with_context(|| format!("parsing Channel {}", id.0));
@@ -174,18 +498,25 @@ impl TryFrom<(TempMap, ChannelID)> for Channel {
 
         Ok(Self {
             id: id,
-            name: get_str("ChannelName")?,
+            name: get_str(tags.channel_name)?,
             // originally, these are in meters? do this check dynamically?
             res: (
-                get_f64("ImageResolutionX")? * 1e6,
-                get_f64("ImageResolutionY")? * 1e6,
+                get_f64(tags.resolution.0)? * 1e6,
+                get_f64(tags.resolution.1)? * 1e6,
             ),
-            mag: get_u16("ObjectiveMagnification")?,
+            mag: get_u16(tags.magnification)?,
+            size: tags.image_size.and_then(|(x, y)| {
+                get_u16(x)
+                    .ok()
+                    .zip(get_u16(y).ok())
+                    .map(|(x, y)| (u32::from(x), u32::from(y)))
+            }),
+            max_intensity: value.get(tags.max_intensity).and_then(|s| s.parse().ok()),
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Image {
     pub row: u16,
     pub col: u16,
@@ -197,43 +528,103 @@ pub struct Image {
     pub position: [f64; 4], // [x, y, z, abs_z] all in meters... until dynamic?
 }
 
-impl TryFrom<TempMap> for Image {
-    type Error = anyhow::Error;
+/// The `<Image>` child elements that are kept. Matching tag names onto this
+/// lets each image fill a fixed set of fields instead of a map of strings.
+#[derive(Debug, Clone, Copy)]
+enum ImageTag {
+    Row,
+    Col,
+    Field,
+    Plane,
+    Timepoint,
+    Channel,
+    Url,
+    PositionX,
+    PositionY,
+    PositionZ,
+    AbsPositionZ,
+}
 
-    fn try_from(value: TempMap) -> std::result::Result<Self, Self::Error> {
-        let get_str = |key| get_string(&value, key).context("parsing Image");
-        let get_u8 = |key| get_u8(&value, key).context("parsing Image");
-        let get_u16 = |key| get_u16(&value, key).context("parsing Image");
-        let get_u32 = |key| get_u32(&value, key).context("parsing Image");
-        let get_f64 = |key| get_f64(&value, key).context("parsing Image");
+/// Partially parsed `<Image>` entry
+#[derive(Debug, Default)]
+struct ImageBuilder {
+    row: Option<u16>,
+    col: Option<u16>,
+    field: Option<u32>,
+    plane: Option<u16>,
+    timepoint: Option<u32>,
+    channel: Option<ChannelID>,
+    url: Option<String>,
+    position: [Option<f64>; 4],
+}
 
-        let position = [
-            get_f64("PositionX")?,
-            get_f64("PositionY")?,
-            get_f64("PositionZ")?,
-            get_f64("AbsPositionZ")?,
-        ];
+impl ImageBuilder {
+    fn set(&mut self, tag: ImageTag, val: &str) -> Result<()> {
+        fn num<T: std::str::FromStr>(tag: ImageTag, val: &str) -> Result<Option<T>>
+        where
+            T::Err: std::error::Error + Send + Sync + 'static,
+        {
+            val.parse::<T>()
+                .map(Some)
+                .with_context(|| format!("parsing {:?} <{}>", tag, val))
+        }
 
-        Ok(Self {
-            row: get_u16("Row")?,
-            col: get_u16("Col")?,
-            field: get_u32("FieldID")?,
-            plane: get_u16("PlaneID")?,
-            timepoint: get_u32("TimepointID")?,
-            channel: get_u8("ChannelID").map(ChannelID)?,
-            url: get_str("URL")?,
-            position,
+        match tag {
+            ImageTag::Row => self.row = num(tag, val)?,
+            ImageTag::Col => self.col = num(tag, val)?,
+            ImageTag::Field => self.field = num(tag, val)?,
+            ImageTag::Plane => self.plane = num(tag, val)?,
+            ImageTag::Timepoint => self.timepoint = num(tag, val)?,
+            ImageTag::Channel => self.channel = num(tag, val)?.map(ChannelID),
+            ImageTag::Url => self.url = Some(val.to_string()),
+            ImageTag::PositionX => self.position[0] = num(tag, val)?,
+            ImageTag::PositionY => self.position[1] = num(tag, val)?,
+            ImageTag::PositionZ => self.position[2] = num(tag, val)?,
+            ImageTag::AbsPositionZ => self.position[3] = num(tag, val)?,
+        }
+
+        Ok(())
+    }
+
+    fn build(self, version: IndexVersion) -> Result<Image> {
+        fn req<T>(v: Option<T>, key: &str) -> Result<T> {
+            v.ok_or_else(|| anyhow!("Missing key <{}>", key))
+                .context("parsing Image")
+        }
+        let [x, y, z, abs_z] = self.position;
+        // older exports only have the relative z position
+        let abs_z = match version {
+            IndexVersion::Legacy(_) => abs_z.or(z),
+            IndexVersion::Current(_) => abs_z,
+        };
+
+        Ok(Image {
+            row: req(self.row, "Row")?,
+            col: req(self.col, "Col")?,
+            field: req(self.field, "FieldID")?,
+            plane: req(self.plane, "PlaneID")?,
+            timepoint: req(self.timepoint, "TimepointID")?,
+            channel: req(self.channel, "ChannelID")?,
+            url: req(self.url, "URL")?,
+            position: [
+                req(x, "PositionX")?,
+                req(y, "PositionY")?,
+                req(z, "PositionZ")?,
+                req(abs_z, "AbsPositionZ")?,
+            ],
         })
     }
 }
 
-#[derive(Debug, Clone, Default, serde::Serialize)]
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
 pub struct WellInfo {
     pub row: u8,
     pub col: u8,
     pub fields: HashSet<u32>,
     pub planes: HashSet<u16>,
     pub timepoints: HashSet<u32>,
+    // from an imported plate layout
+    pub layout: Option<WellLayout>,
 }
 
 impl WellInfo {
@@ -247,16 +638,25 @@ impl WellInfo {
 }
 
 // ###### Parser Functions ######
-fn parse_plates<R: Read>(rdr: &mut xml::EventReader<R>) -> Result<Plate> {
-    use xml::reader::XmlEvent::*;
+/// Next event, with CDATA turned into text like any other element content.
+/// The event borrows `buf`, so names and text aren't copied unless kept.
+fn next_event<'b, R: Read>(rdr: &mut XmlReader<R>, buf: &'b mut Vec<u8>) -> Result<Event<'b>> {
+    buf.clear();
+    match rdr.read_event_into(buf)? {
+        Event::CData(data) => Ok(Event::Text(data.escape()?)),
+        evt => Ok(evt),
+    }
+}
 
+fn parse_plates<R: Read>(rdr: &mut XmlReader<R>, version: IndexVersion) -> Result<Plate> {
+    let mut buf = Vec::new();
     let mut output = vec![];
     let mut state: Option<TempMap> = None;
     let mut field: Option<String> = None;
 
     loop {
-        match rdr.next()? {
-            StartElement { name, .. } if name.local_name == "Plate" => {
+        match next_event(rdr, &mut buf)? {
+            Event::Start(e) if e.local_name().as_ref() == b"Plate" => {
                 if state.is_some() {
                     bail!("Plate tag opened without finishing prior plate");
                 } else {
@@ -264,37 +664,41 @@ fn parse_plates<R: Read>(rdr: &mut xml::EventReader<R>) -> Result<Plate> {
                 }
             }
             // skip the well tag
-            StartElement { name, .. } if name.local_name == "Well" => (),
+            Event::Start(e) if e.local_name().as_ref() == b"Well" => (),
 
             // fields of the struct, basically...
-            StartElement { name, .. } => field = Some(name.local_name),
-            Characters(data) => {
+            Event::Start(e) => {
+                field = Some(String::from_utf8_lossy(e.local_name().as_ref()).into_owned())
+            }
+            Event::Text(data) => {
+                let data = data.unescape()?;
                 let k = field
                     .take()
                     .ok_or_else(|| anyhow!("Missing field for data: {}", &data))?;
 
                 state = state.map(|mut s| {
-                    s.insert(k, data);
+                    s.insert(k, data.into_owned());
                     s
                 });
                 // error for no state?
             }
-            // don't need EndElement since we took the name of the field
+            // don't need the closing tag since we took the name of the field
 
             // plate end
-            EndElement { name } if name.local_name == "Plate" => {
+            Event::End(e) if e.local_name().as_ref() == b"Plate" => {
                 let plate = state
                     .take()
                     .ok_or_else(|| anyhow!("Missing state when plate tag closed"))
-                    .and_then(Plate::try_from)
+                    .and_then(|s| Plate::try_from((s, version)))
                     .context("issusing converting dict into Plate")?;
 
                 output.push(plate);
             }
             // array of plates end
-            EndElement { name } if name.local_name == "Plates" => {
+            Event::End(e) if e.local_name().as_ref() == b"Plates" => {
                 break;
             }
+            Event::Eof => bail!("File ended before </Plates>"),
 
             // ignore everything else...
             _ => (),
@@ -313,94 +717,102 @@ fn parse_plates<R: Read>(rdr: &mut xml::EventReader<R>) -> Result<Plate> {
         .ok_or_else(|| anyhow!("Found no plates in <Plates> section"))
 }
 
-fn parse_maps<R: Read>(rdr: &mut xml::EventReader<R>) -> Result<ChanMap> {
-    use xml::reader::XmlEvent::*;
-
-    fn find_channel_id(attr: &[xml::attribute::OwnedAttribute]) -> Result<ChannelID> {
-        attr.iter()
-            .find(|a| a.name.local_name == "ChannelID")
-            .ok_or_else(|| anyhow!("No ChannelID in Entry attributes"))
-            .and_then(|a| a.value.parse::<u8>().context("parsing channel id"))
-            .map(ChannelID)
+fn parse_maps<R: Read>(rdr: &mut XmlReader<R>, version: IndexVersion) -> Result<ChanMap> {
+    fn find_channel_id(entry: &quick_xml::events::BytesStart) -> Result<ChannelID> {
+        for attr in entry.attributes() {
+            let attr = attr.context("reading Entry attributes")?;
+            if attr.key.local_name().as_ref() == b"ChannelID" {
+                return attr
+                    .unescape_value()?
+                    .parse::<u8>()
+                    .context("parsing channel id")
+                    .map(ChannelID);
+            }
+        }
+        bail!("No ChannelID in Entry attributes")
     }
 
+    let mut buf = Vec::new();
     let mut raw: HashMap<ChannelID, TempMap> = HashMap::new();
     let mut channel: Option<ChannelID> = None;
     let mut field: Option<String> = None;
 
     loop {
-        match rdr.next()? {
+        match next_event(rdr, &mut buf)? {
             // get the channel id
             // <Entry ChannelID=''>
-            StartElement {
-                name, attributes, ..
-            } if name.local_name == "Entry" => {
-                channel = Some(find_channel_id(&attributes)?);
+            Event::Start(e) if e.local_name().as_ref() == b"Entry" => {
+                channel = Some(find_channel_id(&e)?);
             }
             // get the field name if in an entry
-            StartElement { name, .. } if channel.is_some() => field = Some(name.local_name),
+            Event::Start(e) if channel.is_some() => {
+                field = Some(String::from_utf8_lossy(e.local_name().as_ref()).into_owned())
+            }
             // put info into the map if there is a channel and a field
-            Characters(val) if channel.is_some() && field.is_some() => {
+            Event::Text(val) if channel.is_some() && field.is_some() => {
                 let chan = channel.clone().unwrap();
                 let key = field.take().unwrap();
                 let map = raw.entry(chan).or_default();
-                map.insert(key, val);
+                map.insert(key, val.unescape()?.into_owned());
             }
 
             // </Entry>
-            EndElement { name } if name.local_name == "Entry" => {
+            Event::End(e) if e.local_name().as_ref() == b"Entry" => {
                 channel = None;
             }
             // </Maps>
-            EndElement { name } if name.local_name == "Maps" => {
+            Event::End(e) if e.local_name().as_ref() == b"Maps" => {
                 break;
             }
+            Event::Eof => bail!("File ended before </Maps>"),
             _ => (),
         }
     }
 
     raw.into_iter()
-        .map(|(k, temp)| Channel::try_from((temp, k)).map(|v| (k, v)))
+        .map(|(k, temp)| Channel::try_from((temp, k, version)).map(|v| (k, v)))
         .collect()
 }
 
-fn parse_images<R: Read>(rdr: &mut xml::EventReader<R>) -> Result<Vec<Image>> {
-    use xml::reader::XmlEvent::*;
-
+fn parse_images<R: Read>(rdr: &mut XmlReader<R>, version: IndexVersion) -> Result<Vec<Image>> {
+    let mut buf = Vec::new();
     let mut output = Vec::with_capacity(1024);
-    let mut state: Option<TempMap> = None;
-    let mut field: Option<String> = None;
+    let mut state: Option<ImageBuilder> = None;
+    // `None` while inside an element we don't care about
+    let mut field: Option<ImageTag> = None;
 
     loop {
-        match rdr.next()? {
+        match next_event(rdr, &mut buf)? {
             // <Image Version="">
-            StartElement { name, .. } if name.local_name == "Image" => {
-                state = Some(TempMap::with_capacity(32));
+            Event::Start(e) if e.local_name().as_ref() == b"Image" => {
+                state = Some(ImageBuilder::default());
             }
             // <Field>
-            StartElement { name, .. } if state.is_some() => field = Some(name.local_name),
+            Event::Start(e) if state.is_some() => {
+                field = version.image_tag(e.local_name().as_ref())
+            }
 
-            Characters(v) if field.is_some() && state.is_some() => {
-                let k = field.take().unwrap();
-                state = state.map(|mut s| {
-                    s.insert(k, v);
-                    s
-                });
+            Event::Text(v) if field.is_some() => {
+                let tag = field.take().unwrap();
+                if let Some(s) = state.as_mut() {
+                    s.set(tag, &v.unescape()?)?;
+                }
             }
 
             // </Image>
-            EndElement { name } if name.local_name == "Image" => {
+            Event::End(e) if e.local_name().as_ref() == b"Image" => {
                 let s = state
                     .take()
                     .ok_or_else(|| anyhow!("Missing state after closing Image tag"))?;
 
-                output.push(Image::try_from(s).context("parsing data to Image")?);
+                output.push(s.build(version).context("parsing data to Image")?);
             }
 
             // </Images>
-            EndElement { name } if name.local_name == "Images" => {
+            Event::End(e) if e.local_name().as_ref() == b"Images" => {
                 break;
             }
+            Event::Eof => bail!("File ended before </Images>"),
             _ => (),
         }
     }
@@ -443,6 +855,7 @@ fn summarize_wells(wells: &PlateMap<WellInfo>) -> (u16, u16, u16) {
 #[derive(Debug, serde::Serialize)]
 pub struct XmlInfo {
     pub name: String,
+    pub version: String,
     pub rows: u8,
     pub cols: u8,
     pub fields: u16,
@@ -450,18 +863,25 @@ pub struct XmlInfo {
     pub timepoints: u16,
     wells: PlateVec<WellInfo>,
     pub channels: Vec<Channel>,
-    // problem_wells? something missing fields or stacks...
+    // (row, col) of wells that are missing images or have duplicates
+    pub problem_wells: Vec<(u16, u16)>,
 }
 
-impl From<&Harmony> for XmlInfo {
-    fn from(h: &Harmony) -> Self {
+impl From<(&Harmony, &ValidationReport)> for XmlInfo {
+    fn from(value: (&Harmony, &ValidationReport)) -> Self {
+        let (h, report) = value;
         let (r, c) = (h.plate.rows as usize, h.plate.cols as usize);
         let mut wells = vec![vec![None; c]; r];
 
         for (&(r, c), well) in h.wells.iter() {
             let (r, c) = (r as usize, c as usize);
-            let (r, c) = (r - 1, c - 1);
-            wells[r][c] = Some(well.clone());
+            // images outside of the plate show up in the validation report instead
+            if let Some(w) = wells
+                .get_mut(r.wrapping_sub(1))
+                .and_then(|row| row.get_mut(c.wrapping_sub(1)))
+            {
+                *w = Some(well.clone());
+            }
         }
 
         let mut channels: Vec<Channel> = h.channels.values().cloned().collect();
@@ -469,6 +889,7 @@ impl From<&Harmony> for XmlInfo {
 
         Self {
             name: h.plate.name.clone(),
+            version: h.version.to_string(),
             rows: r as u8,
             cols: c as u8,
             fields: h.fields_per_well,
@@ -476,19 +897,98 @@ impl From<&Harmony> for XmlInfo {
             timepoints: h.timepoints,
             wells,
             channels,
+            problem_wells: report.problem_wells(),
         }
     }
 }
 
-#[tauri::command]
-pub async fn parse_xml(path: &str, state: State<'_, Mutex<AppState>>) -> Result<(), String> {
-    let path = Path::new(path);
+/// Progress of reading the export XML, sent to the frontend while parsing
+#[derive(Clone, serde::Serialize, Copy)]
+#[serde(rename_all = "camelCase", tag = "event", content = "data")]
+pub enum ParseEvent {
+    Progress { read: u64, total: u64 },
+    Finished { images: usize },
+}
 
-    let info = Harmony::from_xml_path(&path).map_err(|e| format!("{:?}", e))?;
+/// Read the export at `path`, going through the parse cache in `cache_dir` if given
+pub fn load_harmony(
+    path: &Path,
+    cache_dir: Option<&Path>,
+    progress: impl FnMut(u64, u64),
+) -> Result<Harmony> {
+    if let Some(hm) = cache_dir.and_then(|dir| cache::load(dir, path)) {
+        tracing::info!(path = %path.display(), "using cached parse of export");
+        return Ok(hm);
+    }
+
+    let start = std::time::Instant::now();
+    tracing::info!(path = %path.display(), "parsing export");
+
+    let hm = Harmony::from_xml_path(path, progress).inspect_err(
+        |e| tracing::error!(path = %path.display(), error = ?e, "failed to parse export"),
+    )?;
+    tracing::info!(
+        path = %path.display(),
+        version = %hm.version,
+        images = hm.images.len(),
+        secs = start.elapsed().as_secs_f64(),
+        "parsed export"
+    );
+
+    // the cache is only a shortcut, so failing to write it isn't an error
+    if let Some(dir) = cache_dir {
+        if let Err(e) = cache::store(dir, path, &hm) {
+            tracing::warn!(error = ?e, "failed to cache parsed export");
+        }
+    }
+
+    Ok(hm)
+}
+
+/// Parse the export at `path` on a blocking thread, reporting progress to the
+/// frontend, and check it for problems
+pub async fn parse_in_background(
+    path: PathBuf,
+    on_event: IpcChannel<ParseEvent>,
+    app: &AppHandle,
+) -> Result<(Harmony, ValidationReport), AppError> {
+    let cache_dir = app.path().app_cache_dir().ok();
+
+    // parsing large plates takes a while, so keep it off of the async runtime
+    let progress = on_event.clone();
+    let info = tauri::async_runtime::spawn_blocking(move || {
+        load_harmony(&path, cache_dir.as_deref(), |read, total| {
+            // progress is best effort; a closed channel shouldn't stop the parse
+            let _ = progress.send(ParseEvent::Progress { read, total });
+        })
+    })
+    .await??;
+
+    let report = ValidationReport::new(&info);
+    if !report.is_ok() {
+        tracing::warn!(plate = %info.plate.name, "problems found in export:\n{}", report);
+    }
+
+    let _ = on_event.send(ParseEvent::Finished {
+        images: info.images.len(),
+    });
+
+    Ok((info, report))
+}
+
+#[tauri::command]
+pub async fn parse_xml(
+    path: PathBuf,
+    on_event: IpcChannel<ParseEvent>,
+    app: AppHandle,
+    state: State<'_, Mutex<AppState>>,
+) -> Result<(), AppError> {
+    let (info, report) = parse_in_background(path, on_event, &app).await?;
 
     // store state so that images from selected wells can be fetched later
     let mut state = state.lock().await;
-    state.info = Some(info);
+    state.info = Some(Arc::new(info));
+    state.report = Some(report);
 
     Ok(())
 }
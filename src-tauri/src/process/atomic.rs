@@ -0,0 +1,127 @@
+//! Outputs are written to a temp file next to them and renamed into place once
+//! complete, so a crash or a failed export never leaves a partial file under an
+//! output's name. A failed write removes its own temp file; ones left by exports
+//! the app was closed or crashed during are cleaned up when it next starts.
+//! Exports don't clean up when they start, as another one may be writing into
+//! the same folder.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use anyhow::{Context, Result};
+
+use crate::error::PathContext;
+
+/// Added to the name of an output while it's being written
+pub const TEMP_SUFFIX: &str = ".harmony-dl-part";
+
+fn temp_path(output: &Path) -> PathBuf {
+    let mut name = OsString::from(output.as_os_str());
+    name.push(TEMP_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Write `output` by having `write` write to the temp file it's given
+pub fn write(output: &Path, write: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let tmp = temp_path(output);
+
+    let res = write(&tmp).and_then(|_| {
+        fs::rename(&tmp, output).with_context(|| PathContext::new("moving into place", output))
+    });
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res
+}
+
+/// Remove temp files left in `dir` by an export that didn't finish
+fn clean_up(dir: &Path) -> Result<usize> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        // nothing has been written yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).with_context(|| PathContext::new("listing", dir)),
+    };
+
+    let mut removed = 0;
+    for entry in entries {
+        let path = entry
+            .with_context(|| PathContext::new("listing", dir))?
+            .path();
+        if path.to_string_lossy().ends_with(TEMP_SUFFIX) {
+            fs::remove_file(&path).with_context(|| PathContext::new("removing", &path))?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// File listing the output folders of running exports, if the app keeps one
+static RUNNING_FILE: OnceLock<PathBuf> = OnceLock::new();
+static RUNNING: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Remove temp files left by the exports listed in `file` by an earlier run of
+/// the app, and keep listing running exports there from now on
+pub fn clean_up_interrupted(file: PathBuf) {
+    let dirs: Vec<PathBuf> = File::open(&file)
+        .ok()
+        .and_then(|f| serde_json::from_reader(BufReader::new(f)).ok())
+        .unwrap_or_default();
+
+    let running = RUNNING.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    // exports the app was closed or crashed during, unless one has been
+    // started into the same folder since
+    for dir in dirs.iter().filter(|d| !running.contains(d)) {
+        match clean_up(dir) {
+            Ok(0) => (),
+            Ok(files) => tracing::info!(?dir, files, "removed partial outputs"),
+            Err(e) => tracing::warn!(?dir, error = ?e, "couldn't remove partial outputs"),
+        }
+    }
+
+    if let Some(parent) = file.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+    let _ = RUNNING_FILE.set(file);
+    save_running(&running);
+}
+
+fn save_running(dirs: &[PathBuf]) {
+    let Some(file) = RUNNING_FILE.get() else {
+        return;
+    };
+    let res = write(file, |tmp| {
+        let json = serde_json::to_vec(dirs).context("serializing running exports")?;
+        fs::write(tmp, json).with_context(|| PathContext::new("writing", tmp))
+    });
+    if let Err(e) = res {
+        tracing::warn!(error = ?e, "couldn't save the list of running exports");
+    }
+}
+
+/// Lists an export into `dir` as running until it's dropped
+pub struct Running(PathBuf);
+
+impl Running {
+    pub fn new(dir: &Path) -> Self {
+        let mut running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+        running.push(dir.to_path_buf());
+        save_running(&running);
+        Self(dir.to_path_buf())
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let mut running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(i) = running.iter().position(|d| *d == self.0) {
+            running.remove(i);
+        }
+        save_running(&running);
+    }
+}
@@ -0,0 +1,90 @@
+//! Free space checks on the drive being exported to, so that running out of
+//! space is caught before an export starts, or pauses it, instead of ending in
+//! an IO error halfway through.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+
+/// Space to leave free on the output drive
+pub const MIN_FREE_BYTES: u64 = 1_000_000_000;
+
+/// Bytes available on the drive `dir` is on. It doesn't need to exist yet.
+pub fn available(dir: &Path) -> Result<u64> {
+    let existing = dir
+        .ancestors()
+        .find(|d| d.exists())
+        .unwrap_or(Path::new("."));
+    fs2::available_space(existing)
+        .with_context(|| format!("getting free space of <{}>", existing.display()))
+}
+
+/// Check that `needed` bytes fit in `dir`, leaving `MIN_FREE_BYTES` to spare.
+/// If the export doesn't record how big its images are, this can only check
+/// for the spare space.
+pub fn check_fits(dir: &Path, needed: Option<u64>) -> Result<()> {
+    let needed = needed.unwrap_or_else(|| {
+        tracing::warn!("image sizes are unknown, so only checking for the minimum free space");
+        0
+    });
+    let free = available(dir)?;
+    if free < needed + MIN_FREE_BYTES {
+        bail!(
+            "Not enough space for the export in <{}>: it needs about {:.1} GB, but only {:.1} GB is free",
+            dir.display(),
+            needed as f64 / 1e9,
+            free as f64 / 1e9
+        );
+    }
+    Ok(())
+}
+
+/// Keeps an eye on free space during an export
+pub struct DiskMonitor {
+    dir: PathBuf,
+    last_check: Mutex<Instant>,
+}
+
+impl DiskMonitor {
+    const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+    /// How often to look again once space has run low
+    pub const LOW_RECHECK: Duration = Duration::from_secs(30);
+
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            last_check: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Free bytes, if they're below `required`. This only looks every so
+    /// often, as it's called for every image.
+    pub fn low_space(&self, required: u64) -> Option<u64> {
+        {
+            // another thread is already looking
+            let Ok(mut last) = self.last_check.try_lock() else {
+                return None;
+            };
+            if last.elapsed() < Self::CHECK_INTERVAL {
+                return None;
+            }
+            *last = Instant::now();
+        }
+        self.low_space_now(required)
+    }
+
+    pub fn low_space_now(&self, required: u64) -> Option<u64> {
+        match available(&self.dir) {
+            Ok(free) if free < required => Some(free),
+            Ok(_) => None,
+            Err(e) => {
+                tracing::warn!(error = ?e, "couldn't check free space");
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,418 @@
+//! Condition based image selection, e.g. "every other field in planes 5-20,
+//! except the edge wells". Expressions can be sent from the frontend as JSON,
+//! or parsed from a string like
+//!
+//! ```text
+//! field in 1..9/2 and plane in 5..20 and row in B..G and not edge
+//! ```
+//!
+//! Sets are comma separated values or inclusive `start..end` ranges, with an
+//! optional `/step`. Rows can also be given as letters, e.g. `row in B..G`.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    layout::parse_row,
+    parse_xml::{Harmony, Image},
+};
+
+/// A property of an image that can be selected on
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Dim {
+    Row,
+    Col,
+    Field,
+    Plane,
+    Timepoint,
+    Channel,
+}
+
+impl Dim {
+    fn of(self, img: &Image) -> u32 {
+        match self {
+            Self::Row => img.row as u32,
+            Self::Col => img.col as u32,
+            Self::Field => img.field,
+            Self::Plane => img.plane as u32,
+            Self::Timepoint => img.timepoint,
+            Self::Channel => img.channel.into(),
+        }
+    }
+}
+
+impl FromStr for Dim {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let dim = match s.to_ascii_lowercase().as_str() {
+            "row" | "rows" => Self::Row,
+            "col" | "cols" | "column" | "columns" => Self::Col,
+            "field" | "fields" => Self::Field,
+            "plane" | "planes" => Self::Plane,
+            "timepoint" | "timepoints" | "time" => Self::Timepoint,
+            "channel" | "channels" | "ch" => Self::Channel,
+            _ => bail!("Unknown image property <{}>", s),
+        };
+
+        Ok(dim)
+    }
+}
+
+/// Inclusive range of values, taking every `step`th one from `start`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub start: u32,
+    pub end: u32,
+    #[serde(default = "Span::default_step")]
+    pub step: u32,
+}
+
+impl Span {
+    fn default_step() -> u32 {
+        1
+    }
+
+    fn contains(&self, v: u32) -> bool {
+        (self.start..=self.end).contains(&v) && (v - self.start) % self.step.max(1) == 0
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", tag = "op", content = "args")]
+pub enum FilterExpr {
+    /// Every image
+    All,
+    /// Images from a well on the outside of the plate
+    Edge,
+    /// Images where `dim` is in any of `spans`
+    In {
+        dim: Dim,
+        spans: Vec<Span>,
+    },
+    Not(Box<FilterExpr>),
+    And(Vec<FilterExpr>),
+    Or(Vec<FilterExpr>),
+}
+
+impl FilterExpr {
+    pub fn eval(&self, hm: &Harmony, img: &Image) -> bool {
+        match self {
+            Self::All => true,
+            Self::Edge => {
+                let (rows, cols) = (hm.plate.rows, hm.plate.cols);
+                img.row <= 1 || img.row >= rows || img.col <= 1 || img.col >= cols
+            }
+            Self::In { dim, spans } => {
+                let v = dim.of(img);
+                spans.iter().any(|s| s.contains(v))
+            }
+            Self::Not(e) => !e.eval(hm, img),
+            Self::And(es) => es.iter().all(|e| e.eval(hm, img)),
+            Self::Or(es) => es.iter().any(|e| e.eval(hm, img)),
+        }
+    }
+}
+
+impl FromStr for FilterExpr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser { tokens, pos: 0 };
+
+        let expr = parser.or().context("parsing filter expression")?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(t) => bail!("Unexpected <{}> in filter expression", t),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Open,
+    Close,
+    Comma,
+    DotDot,
+    Slash,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Word(w) => write!(f, "{}", w),
+            Self::Open => write!(f, "("),
+            Self::Close => write!(f, ")"),
+            Self::Comma => write!(f, ","),
+            Self::DotDot => write!(f, ".."),
+            Self::Slash => write!(f, "/"),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let tok = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            ',' => Token::Comma,
+            '/' => Token::Slash,
+            '.' if chars.peek() == Some(&'.') => {
+                chars.next();
+                Token::DotDot
+            }
+            c if c.is_ascii_alphanumeric() || c == '_' => {
+                let mut word = String::from(c);
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || c == '_') {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                Token::Word(word)
+            }
+            _ => bail!("Unexpected character <{}> in filter expression", c),
+        };
+        tokens.push(tok);
+    }
+
+    Ok(tokens)
+}
+
+/// Recursive descent parser, with `not` binding tighter than `and`, which binds
+/// tighter than `or`
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        let t = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| anyhow!("Unexpected end of filter expression"))?;
+        self.pos += 1;
+        Ok(t)
+    }
+
+    fn eat(&mut self, tok: &Token) -> bool {
+        let found = self.peek() == Some(tok);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn eat_word(&mut self, word: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Word(w)) if w.eq_ignore_ascii_case(word));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<FilterExpr> {
+        let mut terms = vec![self.and()?];
+        while self.eat_word("or") {
+            terms.push(self.and()?);
+        }
+
+        Ok(match terms.len() {
+            1 => terms.pop().unwrap(),
+            _ => FilterExpr::Or(terms),
+        })
+    }
+
+    fn and(&mut self) -> Result<FilterExpr> {
+        let mut terms = vec![self.unary()?];
+        while self.eat_word("and") {
+            terms.push(self.unary()?);
+        }
+
+        Ok(match terms.len() {
+            1 => terms.pop().unwrap(),
+            _ => FilterExpr::And(terms),
+        })
+    }
+
+    fn unary(&mut self) -> Result<FilterExpr> {
+        if self.eat_word("not") {
+            return self.unary().map(|e| FilterExpr::Not(Box::new(e)));
+        }
+        if self.eat(&Token::Open) {
+            let e = self.or()?;
+            if !self.eat(&Token::Close) {
+                bail!("Missing closing parenthesis");
+            }
+            return Ok(e);
+        }
+
+        match self.next()? {
+            Token::Word(w) if w.eq_ignore_ascii_case("all") => Ok(FilterExpr::All),
+            Token::Word(w) if w.eq_ignore_ascii_case("edge") => Ok(FilterExpr::Edge),
+            Token::Word(w) => {
+                let dim = w.parse()?;
+                if !self.eat_word("in") {
+                    bail!("Expected <in> after <{}>", w);
+                }
+                let spans = self.spans(dim)?;
+                Ok(FilterExpr::In { dim, spans })
+            }
+            t => bail!("Unexpected <{}> in filter expression", t),
+        }
+    }
+
+    fn spans(&mut self, dim: Dim) -> Result<Vec<Span>> {
+        let mut spans = vec![self.span(dim)?];
+        while self.eat(&Token::Comma) {
+            spans.push(self.span(dim)?);
+        }
+        Ok(spans)
+    }
+
+    fn span(&mut self, dim: Dim) -> Result<Span> {
+        let start = self.value(dim)?;
+        let end = match self.eat(&Token::DotDot) {
+            true => self.value(dim)?,
+            false => start,
+        };
+        let step = match self.eat(&Token::Slash) {
+            true => self.number()?,
+            false => 1,
+        };
+
+        if end < start {
+            bail!("Range {}..{} is empty", start, end);
+        }
+        if step == 0 {
+            bail!("Range step can't be zero");
+        }
+
+        Ok(Span { start, end, step })
+    }
+
+    /// A value of `dim`: a number, or for rows also a letter
+    fn value(&mut self, dim: Dim) -> Result<u32> {
+        match self.peek() {
+            Some(Token::Word(w)) if dim == Dim::Row && w.parse::<u32>().is_err() => {
+                let row = parse_row(w)
+                    .with_context(|| format!("Expected a row number or letter, found <{}>", w))?;
+                self.pos += 1;
+                Ok(row.into())
+            }
+            _ => self.number(),
+        }
+    }
+
+    fn number(&mut self) -> Result<u32> {
+        match self.next()? {
+            Token::Word(w) => w
+                .parse::<u32>()
+                .with_context(|| format!("Expected a number, found <{}>", w)),
+            t => bail!("Expected a number, found <{}>", t),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> FilterExpr {
+        s.parse().unwrap()
+    }
+
+    fn spans(dim: Dim, spans: &[(u32, u32, u32)]) -> FilterExpr {
+        FilterExpr::In {
+            dim,
+            spans: spans
+                .iter()
+                .map(|&(start, end, step)| Span { start, end, step })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and_than_or() {
+        let expected = FilterExpr::Or(vec![
+            FilterExpr::And(vec![
+                FilterExpr::Not(Box::new(FilterExpr::Edge)),
+                spans(Dim::Field, &[(1, 1, 1)]),
+            ]),
+            spans(Dim::Plane, &[(2, 2, 1)]),
+        ]);
+        assert_eq!(parse("not edge and field in 1 or plane in 2"), expected);
+    }
+
+    #[test]
+    fn parentheses_override_precedence() {
+        let expected = FilterExpr::Not(Box::new(FilterExpr::Or(vec![
+            FilterExpr::Edge,
+            FilterExpr::All,
+        ])));
+        assert_eq!(parse("not (edge or all)"), expected);
+    }
+
+    #[test]
+    fn ranges_with_steps() {
+        assert_eq!(
+            parse("field in 1..9/2, 12"),
+            spans(Dim::Field, &[(1, 9, 2), (12, 12, 1)])
+        );
+    }
+
+    #[test]
+    fn span_contains() {
+        let span = Span {
+            start: 3,
+            end: 9,
+            step: 3,
+        };
+        let inside: Vec<u32> = (0..12).filter(|&v| span.contains(v)).collect();
+        assert_eq!(inside, [3, 6, 9]);
+    }
+
+    #[test]
+    fn row_letters() {
+        assert_eq!(parse("row in B..D"), spans(Dim::Row, &[(2, 4, 1)]));
+        assert_eq!(
+            parse("rows in 2, AA"),
+            spans(Dim::Row, &[(2, 2, 1), (27, 27, 1)])
+        );
+    }
+
+    #[test]
+    fn letters_are_only_rows() {
+        assert!("field in A".parse::<FilterExpr>().is_err());
+        assert!("row in A..C/B".parse::<FilterExpr>().is_err());
+    }
+
+    #[test]
+    fn empty_range_and_zero_step_fail() {
+        assert!("field in 5..2".parse::<FilterExpr>().is_err());
+        assert!("field in 1..5/0".parse::<FilterExpr>().is_err());
+    }
+
+    #[test]
+    fn trailing_tokens_fail() {
+        assert!("edge edge".parse::<FilterExpr>().is_err());
+        assert!("field in 1 )".parse::<FilterExpr>().is_err());
+        assert!("field in 1 and".parse::<FilterExpr>().is_err());
+    }
+}
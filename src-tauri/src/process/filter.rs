@@ -1,27 +1,81 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
+use super::{expr::FilterExpr, sample::FieldSampling};
 use crate::parse_xml::{ChannelID, Harmony, Image};
 
+/// Selects images to export. Each set left out selects everything, so that
+/// e.g. only an `expr` needs to be given.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ImageFilter {
-    pub channels: HashSet<ChannelID>,
+    #[serde(default)]
+    pub channels: Option<HashSet<ChannelID>>,
     // (row, col) [one indexed]
-    pub wells: HashSet<(u16, u16)>,
-    pub fields: HashSet<u32>,
-    pub planes: HashSet<u16>,
+    #[serde(default)]
+    pub wells: Option<HashSet<(u16, u16)>>,
+    #[serde(default)]
+    pub fields: Option<HashSet<u32>>,
+    #[serde(default)]
+    pub planes: Option<HashSet<u16>>,
+    // per channel replacements for `fields` and `planes`, e.g. for only taking
+    // the middle plane of a brightfield channel
+    #[serde(default)]
+    pub channel_fields: HashMap<ChannelID, HashSet<u32>>,
+    #[serde(default)]
+    pub channel_planes: HashMap<ChannelID, HashSet<u16>>,
+    // compounds or conditions from the plate layout; `None` doesn't filter
+    #[serde(default)]
+    pub conditions: Option<HashSet<String>>,
+    // further narrows down the images selected by the sets above
+    #[serde(default)]
+    pub expr: Option<FilterExpr>,
+    // only take a subset of the selected fields in each well, per channel
+    #[serde(default)]
+    pub sampling: Option<FieldSampling>,
 }
 
 impl ImageFilter {
     pub fn filter_images<'a>(&self, hm: &'a Harmony) -> Vec<&'a Image> {
-        hm.images
+        let imgs = hm
+            .images
             .iter()
             .filter(|img| {
-                self.channels.contains(&img.channel)
-                    & self.wells.contains(&(img.row, img.col))
-                    & self.fields.contains(&img.field)
-                    & self.planes.contains(&img.plane)
+                in_set(self.channels.as_ref(), &img.channel)
+                    & in_set(self.wells.as_ref(), &(img.row, img.col))
+                    & in_set(self.fields_for(img.channel), &img.field)
+                    & in_set(self.planes_for(img.channel), &img.plane)
+                    & self.matches_layout(hm, img)
+                    & self.expr.as_ref().is_none_or(|e| e.eval(hm, img))
             })
-            .collect()
+            .collect();
+
+        match self.sampling {
+            Some(ref s) => s.apply(imgs),
+            None => imgs,
+        }
+    }
+
+    /// Fields to take from `channel`, with `None` being every field
+    pub fn fields_for(&self, channel: ChannelID) -> Option<&HashSet<u32>> {
+        self.channel_fields.get(&channel).or(self.fields.as_ref())
+    }
+
+    /// Planes to take from `channel`, with `None` being every plane
+    pub fn planes_for(&self, channel: ChannelID) -> Option<&HashSet<u16>> {
+        self.channel_planes.get(&channel).or(self.planes.as_ref())
     }
+
+    fn matches_layout(&self, hm: &Harmony, img: &Image) -> bool {
+        match self.conditions {
+            None => true,
+            Some(ref wanted) => hm
+                .well_layout(img.row, img.col)
+                .is_some_and(|l| wanted.iter().any(|c| l.matches(c))),
+        }
+    }
+}
+
+/// Is `v` in `set`, where no set takes everything?
+fn in_set<T: Eq + std::hash::Hash>(set: Option<&HashSet<T>>, v: &T) -> bool {
+    set.is_none_or(|s| s.contains(v))
 }
@@ -1,4 +1,4 @@
-use crate::parse_xml::{ChanMap, Harmony, Image};
+use crate::parse_xml::{Harmony, Image};
 
 #[derive(Copy, Clone)]
 pub struct ImgNameFmt<'a> {
@@ -8,14 +8,14 @@ pub struct ImgNameFmt<'a> {
     t: usize,
     f: usize,
     p: usize,
-    cmap: &'a ChanMap,
+    hm: &'a Harmony,
 }
 
 impl<'a> ImgNameFmt<'a> {
     pub fn fname_plane(&self, img: &Image) -> String {
-        format!(
+        let name = format!(
             "{}-R{:0rw$}C{:0cw$}T{:0tw$}F{:0fw$}P{:0pw$}",
-            &self.cmap[&img.channel].name,
+            &self.hm.channels[&img.channel].name,
             img.row,
             img.col,
             img.timepoint,
@@ -26,7 +26,17 @@ impl<'a> ImgNameFmt<'a> {
             tw = self.t,
             fw = self.f,
             pw = self.p
-        )
+        );
+
+        // tag the file with what's in the well, if a plate layout was imported
+        match self
+            .hm
+            .well_layout(img.row, img.col)
+            .and_then(|l| l.label())
+        {
+            Some(label) => format!("{}-{}", name, label),
+            None => name,
+        }
     }
 }
 
@@ -39,7 +49,7 @@ impl<'a> From<&'a Harmony> for ImgNameFmt<'a> {
             t: numdig(hm.timepoints as usize),
             f: numdig(hm.fields_per_well as usize),
             p: numdig(hm.planes_per_field as usize),
-            cmap: &hm.channels,
+            hm,
         }
     }
 }
@@ -6,47 +6,64 @@ use std::{
 
 use anyhow::{Context, Result};
 use rayon::prelude::*;
-use tauri::ipc::Channel;
 
-use crate::parse_xml::{Harmony, Image};
+use crate::{
+    error::PathContext,
+    parse_xml::{Harmony, Image},
+};
+
+use super::{atomic, imgfmt::ImgNameFmt, progress::Tracker, verify::Fetcher};
 
-use super::{imgfmt::ImgNameFmt, DLEvent};
+type Info<'a> = (&'a Tracker, &'a Fetcher<'a>);
+type Img<'a> = (&'a Image, PathBuf);
 
-type Info<'a> = (Channel<DLEvent>, &'a Path);
-type Img<'a> = (&'a Image, String);
+fn dl_tiff((tracker, fetcher): &mut Info, (img, output): Img) -> Result<()> {
+    tracker.wait_if_paused();
 
-fn dl_tiff((evt, outdir): &mut Info, (img, fname): Img) -> Result<()> {
-    let raw = reqwest::blocking::get(&img.url)
-        .and_then(|res| res.bytes())
-        .with_context(|| format!("dowloading image <{}> ({})", &img.url, &fname))?;
+    let fetched = fetcher
+        .fetch(img)
+        .with_context(|| format!("dowloading image <{}> ({})", &img.url, output.display()))?;
+    let raw = fetched.raw;
 
     // TODO: flat field correction....
     // open image -> NDarray YX -> FFC -> Save as TIFF
-    let mut fname = PathBuf::from(fname);
-    fname.set_extension("tiff");
-    let output = outdir.join(&fname);
-
-    fs::File::create(output)
-        .with_context(|| format!("creating output <{}>", fname.display()))
-        .and_then(|mut f| {
-            f.write_all(&raw)
-                .with_context(|| format!("writing raw bytes output <{}>", fname.display()))
-        })
-        .and_then(|_| {
-            evt.send(DLEvent::from(img))
-                .context("sending download progress")
-        })
+
+    atomic::write(&output, |tmp| {
+        let mut f =
+            fs::File::create(tmp).with_context(|| PathContext::new("creating output", tmp))?;
+        f.write_all(&raw)
+            .and_then(|_| f.sync_all())
+            .with_context(|| PathContext::new("writing raw bytes to output", tmp))
+    })
+    .and_then(|_| fetcher.record(&fetched.checked, &output))
+    .and_then(|_| tracker.image_done(img, raw.len()))
+    .and_then(|_| tracker.written(&output))
+    .inspect(|_| tracing::debug!(output = %output.display(), "wrote image"))
 }
 
+/// Output file of each of `imgs`
+pub fn plan<'a>(imgs: &[&'a Image], hm: &Harmony, outdir: &Path) -> Vec<(&'a Image, PathBuf)> {
+    let fmt = ImgNameFmt::from(hm);
+    imgs.iter()
+        // names can have dots in them, e.g. from a concentration, so the
+        // extension is added rather than set
+        .map(|&img| (img, outdir.join(format!("{}.tiff", fmt.fname_plane(img)))))
+        .collect()
+}
+
+/// Download each image into its output file, as planned by `plan`
 pub fn download_tiff_images(
-    imgs: &[&Image],
-    hm: &Harmony,
-    outdir: &Path,
-    event: Channel<DLEvent>,
+    planned: Vec<(&Image, PathBuf)>,
+    fetcher: &Fetcher,
+    tracker: &Tracker,
 ) -> Result<()> {
-    let fmt = ImgNameFmt::from(hm);
-    imgs.into_par_iter()
-        .map(|&img| (img, fmt.fname_plane(img)))
-        .try_for_each_with((event, outdir), dl_tiff)
+    let span = tracing::Span::current();
+    planned
+        .into_par_iter()
+        .try_for_each_with((tracker, fetcher), |info, img| {
+            // rayon threads don't inherit the export span for logging
+            let _guard = span.enter();
+            dl_tiff(info, img)
+        })
         .context("dowloading image")
 }
@@ -1,52 +1,61 @@
-use std::{collections::HashMap, fmt, io::Cursor, path::Path};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{BufWriter, Cursor},
+    path::{Path, PathBuf},
+};
 
 use anyhow::{anyhow, Context, Result};
 use image::{ImageFormat, ImageReader};
 use ndarray::azip;
 use nshare::IntoNdarray2;
 use rayon::iter::IntoParallelIterator;
-use tauri::ipc::Channel;
 
-use super::{array_to_image, DLEvent, YX};
+use super::{
+    array_to_image, atomic,
+    progress::Tracker,
+    verify::{Checked, Fetcher},
+    YX,
+};
 use rayon::prelude::*;
 
-use crate::parse_xml::{ChannelID, Harmony, Image};
+use crate::{
+    error::PathContext,
+    parse_xml::{ChannelID, Harmony, Image},
+};
 
-type Chan = Channel<DLEvent>;
-type MaxAcc = Option<YX>;
+/// Pixels projected so far, and the checked images that went into them
+type MaxAcc = (Option<YX>, Vec<Checked>);
 
 /// Download an Image and project onto the accumulated pixels
-fn max_field(event: Chan, acc: MaxAcc, img: &Image) -> Result<MaxAcc> {
-    let res = reqwest::blocking::get(&img.url)
-        .with_context(|| format!("downloading image from <{}>", &img.url))?;
-    let raw = res
-        .bytes()
-        .context("reading response bytes from image download")?;
-
+fn max_field(tracker: &Tracker, fetcher: &Fetcher, acc: MaxAcc, img: &Image) -> Result<MaxAcc> {
+    // a paused download holds on to the pixels projected so far
+    tracker.wait_if_paused();
+    let fetched = fetcher.fetch(img).context("reading image bytes")?;
+
+    // checked images were already decoded
+    let decoded = match fetched.decoded {
+        Some(decoded) => decoded,
+        None => ImageReader::with_format(Cursor::new(&fetched.raw), ImageFormat::Tiff)
+            .decode()
+            .context("reading raw bytes as TIFF image")?,
+    };
     // the image should be a 16bit intensity image, but maybe this can be configured dynamically?
-    let pixels = ImageReader::with_format(Cursor::new(&raw), ImageFormat::Tiff)
-        .decode()
-        .context("reading raw bytes as TIFF image")?
-        .into_luma16()
-        .into_ndarray2();
-
-    let acc = acc
-        .map(|mut acc| {
-            azip!((a in &mut acc, &b in &pixels) *a = (*a).max(b));
-            acc
+    let pixels = decoded.into_luma16().into_ndarray2();
+
+    let (max, mut checked) = acc;
+    let max = max
+        .map(|mut max| {
+            azip!((a in &mut max, &b in &pixels) *a = (*a).max(b));
+            max
         })
         .or_else(|| Some(pixels));
+    checked.extend(fetched.checked);
 
-    event
-        .send(DLEvent::Plane {
-            r: img.row,
-            c: img.col,
-            f: img.field,
-            p: img.plane,
-        })
-        .unwrap();
+    tracker.image_done(img, fetched.raw.len())?;
 
-    Ok(acc)
+    Ok((max, checked))
 }
 
 #[derive(Hash, Copy, Clone, Eq, PartialEq)]
@@ -77,20 +86,20 @@ impl From<&Image> for ImageKey {
     }
 }
 
-/// Perform a maximum projection for each field. This downloads and projects the images
-/// in parallel. For now, it outputs individual images in `outdir`, but this will evenutally
-/// output either a directory images or one OME Zarr file.
-pub fn max_project(
-    imgs: &[&Image],
-    hm: &Harmony,
-    outdir: &Path,
-    on_event: Channel<DLEvent>,
-) -> Result<()> {
+/// The images of a field that are projected into one output file
+pub struct Projection<'a> {
+    key: ImageKey,
+    pub imgs: Vec<&'a Image>,
+    pub output: PathBuf,
+}
+
+/// Group `imgs` by the projection they are part of, and name its output file
+pub fn plan<'a>(imgs: &[&'a Image], hm: &Harmony, outdir: &Path) -> Vec<Projection<'a>> {
     type Map<'a> = HashMap<ImageKey, Vec<&'a Image>>;
 
     let cmap = &hm.channels;
 
-    let by_field = imgs.into_iter().fold(Map::new(), |mut acc, &img| {
+    let by_field = imgs.iter().fold(Map::new(), |mut acc, &img| {
         let key = ImageKey::from(img);
 
         acc.entry(key)
@@ -100,28 +109,70 @@ pub fn max_project(
         acc
     });
 
-    let project_evt = |acc, img| max_field(on_event.clone(), acc, img);
-
     by_field
-        .into_par_iter()
+        .into_iter()
         .map(|(key, imgs)| {
+            let ImageKey { r, c, ch, t, f } = key;
+            let ch = cmap[&ch].name.as_str();
+            // TODO: holder struct that has the max of each, then formats width dynamically
+            let fname = match hm.well_layout(r, c).and_then(|l| l.label()) {
+                Some(label) => format!("{ch}-R{r:02}C{c:02}T{t:03}F{f:03}-{label}.tiff"),
+                None => format!("{ch}-R{r:02}C{c:02}T{t:03}F{f:03}.tiff"),
+            };
+            Projection {
+                key,
+                imgs,
+                output: outdir.join(fname),
+            }
+        })
+        .collect()
+}
+
+/// Perform a maximum projection for each field. This downloads and projects the images
+/// in parallel. For now, it outputs individual images, but this will evenutally
+/// output either a directory images or one OME Zarr file.
+pub fn max_project(
+    projections: Vec<Projection>,
+    fetcher: &Fetcher,
+    tracker: &Tracker,
+) -> Result<()> {
+    // rayon threads don't inherit the export span for logging
+    let span = tracing::Span::current();
+
+    projections
+        .into_par_iter()
+        .map(|Projection { key, imgs, output }| {
+            let _guard = span.enter();
             imgs.into_iter()
-                .try_fold(None, project_evt)
+                .try_fold((None, Vec::new()), |acc, img| {
+                    max_field(tracker, fetcher, acc, img)
+                })
                 .with_context(|| format!("processing {}", &key))
-                .map(|projection| (key, projection))
+                .map(|(projection, checked)| (key, output, projection, checked))
         })
         .try_for_each(|res| {
-            let (key, projection) = res?;
+            let _guard = span.enter();
+            let (key, output, projection, checked) = res?;
             let projection = projection.ok_or_else(|| anyhow!("missing projection for {}", key))?;
 
             let ImageKey { r, c, ch, t, f } = key;
-            let ch = cmap[&ch].name.as_str();
+            tracker.projected(r, c, t, f, ch)?;
+
             let img = array_to_image(projection);
-            // TODO: holder struct that has the max of each, then formats width dynamically
-            let fname = format!("{ch}-R{r:02}C{c:02}T{t:03}F{f:03}.tiff");
-            let output = outdir.join(&fname);
 
-            img.save_with_format(&output, ImageFormat::Tiff)
-                .with_context(|| format!("saving projection to <{}>", output.display()))
+            atomic::write(&output, |tmp| {
+                let f = File::create(tmp)
+                    .with_context(|| PathContext::new("creating projection", tmp))?;
+                let mut w = BufWriter::new(f);
+                img.write_to(&mut w, ImageFormat::Tiff)
+                    .with_context(|| PathContext::new("saving projection to", tmp))?;
+                w.into_inner()
+                    .map_err(|e| e.into_error())
+                    .and_then(|f| f.sync_all())
+                    .with_context(|| PathContext::new("saving projection to", tmp))
+            })?;
+            fetcher.record(&checked, &output)?;
+            tracing::debug!(output = %output.display(), "wrote projection");
+            tracker.written(&output)
         })
 }
@@ -1,22 +1,49 @@
+pub(crate) mod atomic;
+pub(crate) mod disk;
+mod expr;
 mod filter;
 mod imgfmt;
 mod individual;
 mod max;
+mod plan;
+mod progress;
+mod registry;
+mod sample;
+mod verify;
 
+pub use expr::FilterExpr;
 pub use filter::ImageFilter;
+pub use plan::{plan_download, plan_export, preview_conflicts, DownloadPlan, ExistingFiles};
+pub use progress::{progress_events, Events, Progress};
+pub use registry::{get_job_status, pause_download, resume_download, JobRegistry};
 
-use anyhow::{anyhow, Result};
+use std::sync::Arc;
+
+use anyhow::Result;
 use image::{ImageBuffer, Luma};
 use ndarray::prelude::*;
 use tauri::{async_runtime::Mutex, ipc::Channel, State};
 
-use crate::{parse_xml::Image, AppState};
+use crate::logging::{RUN_LOG_NAME, RUN_SPAN};
+use crate::{
+    error::AppError,
+    parse_xml::{ChannelID, Harmony, Image},
+    AppState,
+};
+use plan::Plan;
+use progress::{PauseReason, Tracker};
+use verify::{Fetcher, Verification};
 
 #[derive(serde::Deserialize, serde::Serialize, Clone)]
 pub struct OutputInfo {
     pub dir: std::path::PathBuf,
     pub action: OutputAction,
     pub format: OutputFormat,
+    /// Check downloaded images before saving them, see `verify`
+    #[serde(default)]
+    pub verify: Option<Verification>,
+    #[serde(default)]
+    pub existing: ExistingFiles,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Clone, Copy)]
@@ -45,20 +72,20 @@ pub struct DownloadInfo {
 }
 
 impl TryFrom<&AppState> for DownloadInfo {
-    type Error = anyhow::Error;
-    fn try_from(state: &AppState) -> Result<Self> {
+    type Error = AppError;
+    fn try_from(state: &AppState) -> std::result::Result<Self, AppError> {
         let info = state
             .info
             .as_ref()
-            .ok_or_else(|| anyhow!("Missing measurement info"))?;
+            .ok_or_else(|| AppError::missing("measurement info"))?;
         let output = state
             .output
             .as_ref()
-            .ok_or_else(|| anyhow!("Missing output info"))?;
+            .ok_or_else(|| AppError::missing("output info"))?;
         let filter = state
             .filter
             .as_ref()
-            .ok_or_else(|| anyhow!("Missing filter info"))?;
+            .ok_or_else(|| AppError::missing("filter info"))?;
 
         let rows = info.plate.rows;
         let cols = info.plate.cols;
@@ -86,49 +113,189 @@ fn array_to_image(arr: YX) -> Int16 {
     ImageBuffer::from_raw(w as u32, h as u32, raw).unwrap()
 }
 
-#[derive(Clone, serde::Serialize, Copy)]
-#[serde(rename_all = "camelCase", tag = "event", content = "data")]
-pub enum DLEvent {
-    Started,
-    Plane { r: u16, c: u16, f: u32, p: u16 },
-    Finished,
+/// A source image that has been downloaded and processed
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct PlaneDone {
+    r: u16,
+    c: u16,
+    f: u32,
+    p: u16,
+    t: u32,
+    ch: ChannelID,
 }
 
-impl From<&Image> for DLEvent {
+impl From<&Image> for PlaneDone {
     fn from(img: &Image) -> Self {
-        Self::Plane {
+        Self {
             r: img.row,
             c: img.col,
             f: img.field,
             p: img.plane,
+            t: img.timepoint,
+            ch: img.channel,
         }
     }
 }
 
+/// A field of which every plane has been projected
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct FieldDone {
+    r: u16,
+    c: u16,
+    f: u32,
+    t: u32,
+    ch: ChannelID,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", tag = "event", content = "data")]
+pub enum DLEvent {
+    /// `images` source images will be processed into `outputs` files
+    Started {
+        images: usize,
+        outputs: usize,
+    },
+    /// How far along the download is, with what has been done since the last update
+    Progress {
+        progress: Progress,
+        planes: Vec<PlaneDone>,
+        projected: Vec<FieldDone>,
+        written: Vec<std::path::PathBuf>,
+    },
+    /// Only `available` bytes are left on the output drive, under the
+    /// `required` minimum; the download is paused until there's more
+    LowDiskSpace {
+        available: u64,
+        required: u64,
+    },
+    /// No new images will be downloaded until the download is resumed
+    Paused {
+        reason: PauseReason,
+    },
+    Resumed,
+    Finished,
+}
+
+/// Download the images selected by `filter`, processing them according to `outinfo`
+pub fn run_export(
+    hm: &Harmony,
+    filter: &ImageFilter,
+    outinfo: &OutputInfo,
+    on_event: Events,
+) -> Result<()> {
+    run_tracked(hm, filter, outinfo, &Tracker::new(on_event))
+}
+
+/// `run_export`, keeping count with a `tracker` that can be queried while it runs
+fn run_tracked(
+    hm: &Harmony,
+    filter: &ImageFilter,
+    outinfo: &OutputInfo,
+    tracker: &Tracker,
+) -> Result<()> {
+    let run_log = outinfo.dir.join(RUN_LOG_NAME);
+    let span = tracing::info_span!(
+        RUN_SPAN,
+        plate = %hm.plate.name,
+        run_log = %run_log.display()
+    );
+    let _guard = span.enter();
+    // other exports can be writing into the same folder, so temp files are
+    // only cleaned up at startup, for the folders listed here
+    let _running = atomic::Running::new(&outinfo.dir);
+
+    let imgs = filter.filter_images(hm);
+
+    let res = Plan::new(&imgs, hm, outinfo)
+        .resolve(outinfo.existing)
+        .and_then(|plan| {
+            let (images, outputs) = (plan.images(), plan.outputs().len());
+            tracing::info!(
+                xml = %hm.source.path().display(),
+                outdir = %outinfo.dir.display(),
+                images,
+                outputs,
+                existing = ?outinfo.existing,
+                "starting export"
+            );
+
+            let (_, disk_bytes) = plan.estimate(hm);
+            disk::check_fits(&outinfo.dir, disk_bytes)?;
+            tracker.watch_disk(
+                disk::DiskMonitor::new(&outinfo.dir),
+                disk_bytes.unwrap_or(0),
+            );
+
+            let fetcher = Fetcher::new(hm, outinfo.verify, &outinfo.dir)?;
+            tracker.started(images, outputs)?;
+            plan.run(&fetcher, tracker)?;
+            fetcher.finish()
+        });
+
+    let p = tracker.progress();
+    match res {
+        Ok(()) => tracing::info!(
+            images = p.images,
+            outputs = p.outputs,
+            bytes = p.bytes,
+            "finished export"
+        ),
+        Err(ref e) => {
+            tracker.failed(e);
+            tracing::error!(images = p.images, outputs = p.outputs, error = ?e, "export failed")
+        }
+    }
+    res?;
+
+    tracker.finished()
+}
+
+/// Copy what an export needs out of `AppState`, so that it's only locked for
+/// as long as that takes
+async fn snapshot(
+    state: &Mutex<AppState>,
+) -> Result<(Arc<Harmony>, ImageFilter, OutputInfo), AppError> {
+    let state = state.lock().await;
+
+    let hm = state
+        .info
+        .clone()
+        .ok_or_else(|| AppError::missing("XML info"))?;
+    let filter = state
+        .filter
+        .clone()
+        .ok_or_else(|| AppError::missing("filter"))?;
+    let outinfo = state
+        .output
+        .clone()
+        .ok_or_else(|| AppError::missing("output info"))?;
+    Ok((hm, filter, outinfo))
+}
+
+/// Run the export set up in `AppState`, resolving once it has finished. The
+/// state is only locked long enough to take a copy of it.
 #[tauri::command]
 pub async fn start_download(
     on_event: Channel<DLEvent>,
     state: State<'_, Mutex<AppState>>,
-) -> Result<(), String> {
-    let state = state.lock().await;
+    registry: State<'_, Mutex<JobRegistry>>,
+) -> Result<(), AppError> {
+    let (hm, filter, outinfo) = snapshot(&state).await?;
 
-    let hm = state.info.as_ref().ok_or("Missing XML Info")?;
-    let filter = state.filter.as_ref().ok_or("Missing Filter")?;
-    let outinfo = state.output.as_ref().ok_or("Missing output info")?;
+    let tracker = registry
+        .lock()
+        .await
+        .start(&outinfo.dir, on_event)?
+        .tracker();
 
-    let imgs = filter.filter_images(&hm);
-
-    on_event
-        .send(DLEvent::Started)
-        .map_err(|_| "sending start DL event")?;
-
-    let res = match outinfo.action {
-        OutputAction::MaxProjection => max::max_project(&imgs, hm, &outinfo.dir, on_event.clone()),
-        OutputAction::IndividualPlanes => {
-            individual::download_tiff_images(&imgs, hm, &outinfo.dir, on_event.clone())
-        }
-    };
+    let worker = tracker.clone();
+    let res =
+        tauri::async_runtime::spawn_blocking(move || run_tracked(&hm, &filter, &outinfo, &worker))
+            .await
+            .map_err(anyhow::Error::from)
+            // a panicking worker hasn't recorded its failure
+            .inspect_err(|e| tracker.failed(e))
+            .and_then(|res| res);
 
-    res.map_err(|e| format!("{:?}", e))
-        .inspect(|_| on_event.send(DLEvent::Finished).unwrap())
+    Ok(res?)
 }
@@ -0,0 +1,303 @@
+//! Working out which output files an export will write, before anything is
+//! downloaded, so that files already in the output folder can be dealt with
+//! up front instead of being silently overwritten, and so that it can be
+//! checked whether an export fits on the target drive.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use tauri::{async_runtime::Mutex, State};
+
+use super::{
+    individual,
+    max::{self, Projection},
+    progress::Tracker,
+    snapshot,
+    verify::Fetcher,
+    ImageFilter, OutputAction, OutputInfo,
+};
+use crate::{
+    error::AppError,
+    parse_xml::{Channel, Harmony, Image},
+    AppState,
+};
+
+/// Harmony writes images as uncompressed TIFFs, which are 16 bit unless their
+/// channel says otherwise
+const DEFAULT_BYTES_PER_PIXEL: u64 = 2;
+
+/// Projections are written as 16 bit, whatever the depth of their planes
+const PROJECTION_BYTES_PER_PIXEL: u64 = 2;
+
+fn bytes_per_pixel(ch: &Channel) -> u64 {
+    match ch.max_intensity {
+        Some(max) => u64::from(u32::BITS - max.leading_zeros())
+            .div_ceil(8)
+            .max(1),
+        None => DEFAULT_BYTES_PER_PIXEL,
+    }
+}
+
+/// What to do about outputs that already exist in the output folder
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ExistingFiles {
+    /// Don't start the export
+    #[default]
+    Fail,
+    Overwrite,
+    /// Leave them be, and don't download their images
+    Skip,
+    /// Write the new output next to it, with a `-1`, `-2`, ... suffix
+    Rename,
+}
+
+pub enum Plan<'a> {
+    Planes(Vec<(&'a Image, PathBuf)>),
+    Projections(Vec<Projection<'a>>),
+}
+
+impl<'a> Plan<'a> {
+    pub fn new(imgs: &[&'a Image], hm: &Harmony, outinfo: &OutputInfo) -> Self {
+        match outinfo.action {
+            OutputAction::MaxProjection => Self::Projections(max::plan(imgs, hm, &outinfo.dir)),
+            OutputAction::IndividualPlanes => {
+                Self::Planes(individual::plan(imgs, hm, &outinfo.dir))
+            }
+        }
+    }
+
+    /// Number of source images that will be downloaded
+    pub fn images(&self) -> usize {
+        match self {
+            Self::Planes(planes) => planes.len(),
+            Self::Projections(projections) => projections.iter().map(|p| p.imgs.len()).sum(),
+        }
+    }
+
+    /// Number of source images that will be downloaded from each well
+    pub fn well_images(&self) -> BTreeMap<(u16, u16), usize> {
+        let imgs: Box<dyn Iterator<Item = &Image>> = match self {
+            Self::Planes(planes) => Box::new(planes.iter().map(|&(img, _)| img)),
+            Self::Projections(projections) => {
+                Box::new(projections.iter().flat_map(|p| p.imgs.iter().copied()))
+            }
+        };
+
+        imgs.fold(BTreeMap::new(), |mut wells, img| {
+            *wells.entry((img.row, img.col)).or_default() += 1;
+            wells
+        })
+    }
+
+    pub fn outputs(&self) -> Vec<&Path> {
+        match self {
+            Self::Planes(planes) => planes.iter().map(|(_, out)| out.as_path()).collect(),
+            Self::Projections(projections) => {
+                projections.iter().map(|p| p.output.as_path()).collect()
+            }
+        }
+    }
+
+    /// Outputs that are already in the output folder
+    pub fn conflicts(&self) -> Vec<PathBuf> {
+        self.outputs()
+            .into_iter()
+            .filter(|out| out.exists())
+            .map(Path::to_path_buf)
+            .collect()
+    }
+
+    /// Deal with outputs that already exist according to `policy`
+    pub fn resolve(mut self, policy: ExistingFiles) -> Result<Self> {
+        match policy {
+            ExistingFiles::Overwrite => (),
+            ExistingFiles::Fail => {
+                let conflicts = self.conflicts();
+                if let Some(first) = conflicts.first() {
+                    bail!(
+                        "{} outputs already exist, e.g. <{}>",
+                        conflicts.len(),
+                        first.display()
+                    );
+                }
+            }
+            ExistingFiles::Skip => match self {
+                Self::Planes(ref mut planes) => planes.retain(|(_, out)| !out.exists()),
+                Self::Projections(ref mut projections) => {
+                    projections.retain(|p| !p.output.exists())
+                }
+            },
+            ExistingFiles::Rename => {
+                let mut taken: HashSet<PathBuf> =
+                    self.outputs().into_iter().map(Path::to_path_buf).collect();
+                let mut rename = |out: &mut PathBuf| {
+                    if out.exists() {
+                        *out = free_name(out, &mut taken);
+                    }
+                };
+                match self {
+                    Self::Planes(ref mut planes) => {
+                        planes.iter_mut().for_each(|(_, out)| rename(out))
+                    }
+                    Self::Projections(ref mut projections) => {
+                        projections.iter_mut().for_each(|p| rename(&mut p.output))
+                    }
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    /// Estimated sizes of the images to download and of the outputs, if the
+    /// export records the size of its images
+    pub fn estimate(&self, hm: &Harmony) -> (Option<u64>, Option<u64>) {
+        let pixels = |img: &Image| {
+            hm.channels
+                .get(&img.channel)
+                .and_then(|ch| ch.size)
+                .map(|(x, y)| u64::from(x) * u64::from(y))
+        };
+        let bytes =
+            |img: &Image| Some(pixels(img)? * bytes_per_pixel(hm.channels.get(&img.channel)?));
+
+        match self {
+            Self::Planes(planes) => {
+                let total = planes.iter().map(|(img, _)| bytes(img)).sum();
+                // the downloaded bytes are written as they are
+                (total, total)
+            }
+            Self::Projections(projections) => {
+                let download = projections
+                    .iter()
+                    .flat_map(|p| &p.imgs)
+                    .map(|img| bytes(img))
+                    .sum();
+                let disk = projections
+                    .iter()
+                    .map(|p| p.imgs.first().and_then(|img| pixels(img)))
+                    .map(|n| n.map(|n| n * PROJECTION_BYTES_PER_PIXEL))
+                    .sum();
+                (download, disk)
+            }
+        }
+    }
+
+    /// Download and write everything planned, on a thread pool of its own so
+    /// that threads blocked by a pause don't hold up the global one
+    pub fn run(self, fetcher: &Fetcher, tracker: &Tracker) -> Result<()> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .thread_name(|i| format!("export-{}", i))
+            .build()
+            .context("starting export threads")?;
+
+        pool.install(|| match self {
+            Self::Planes(planes) => individual::download_tiff_images(planes, fetcher, tracker),
+            Self::Projections(projections) => max::max_project(projections, fetcher, tracker),
+        })
+    }
+}
+
+/// First `name-N.ext` next to `path` that doesn't exist and isn't `taken`
+fn free_name(path: &Path, taken: &mut HashSet<PathBuf>) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default();
+
+    for n in 1.. {
+        let mut name = OsString::from(stem);
+        name.push(format!("-{}", n));
+        if let Some(ext) = path.extension() {
+            name.push(".");
+            name.push(ext);
+        }
+
+        let candidate = path.with_file_name(name);
+        if !candidate.exists() && !taken.contains(&candidate) {
+            taken.insert(candidate.clone());
+            return candidate;
+        }
+    }
+    unreachable!("ran out of suffixes for <{}>", path.display())
+}
+
+/// What an export would do, without downloading anything
+#[derive(Debug, serde::Serialize)]
+pub struct DownloadPlan {
+    /// Files that will be written
+    pub outputs: Vec<PathBuf>,
+    /// Number of source images that will be downloaded
+    pub images: usize,
+    /// (row, col, images) of each well images will be downloaded from
+    pub wells: Vec<(u16, u16, usize)>,
+    /// Estimated bytes to download, if the export records image sizes
+    pub download_bytes: Option<u64>,
+    /// Estimated disk space the outputs need
+    pub disk_bytes: Option<u64>,
+    /// Outputs that already exist
+    pub conflicts: Vec<PathBuf>,
+    pub existing: ExistingFiles,
+}
+
+/// Plan the export of the images `filter` selects, as `run_export` would.
+/// Existing outputs are listed rather than failing the plan.
+pub fn plan_export(
+    hm: &Harmony,
+    filter: &ImageFilter,
+    outinfo: &OutputInfo,
+) -> Result<DownloadPlan> {
+    let imgs = filter.filter_images(hm);
+    let mut plan = Plan::new(&imgs, hm, outinfo);
+
+    let conflicts = plan.conflicts();
+    if outinfo.existing != ExistingFiles::Fail {
+        plan = plan.resolve(outinfo.existing)?;
+    }
+    let (download_bytes, disk_bytes) = plan.estimate(hm);
+
+    Ok(DownloadPlan {
+        outputs: plan.outputs().into_iter().map(Path::to_path_buf).collect(),
+        images: plan.images(),
+        wells: plan
+            .well_images()
+            .into_iter()
+            .map(|((r, c), n)| (r, c, n))
+            .collect(),
+        download_bytes,
+        disk_bytes,
+        conflicts,
+        existing: outinfo.existing,
+    })
+}
+
+/// Plan the export set up in `AppState`. This looks at the output folder, so it
+/// runs on a blocking thread with a copy of the state instead of holding its lock.
+#[tauri::command]
+pub async fn plan_download(state: State<'_, Mutex<AppState>>) -> Result<DownloadPlan, AppError> {
+    let (hm, filter, outinfo) = snapshot(&state).await?;
+
+    let plan = tauri::async_runtime::spawn_blocking(move || plan_export(&hm, &filter, &outinfo))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|res| res)?;
+    Ok(plan)
+}
+
+/// Outputs of the export set up in `AppState` that already exist, and so
+/// would be dealt with by its `ExistingFiles` policy
+#[tauri::command]
+pub async fn preview_conflicts(
+    state: State<'_, Mutex<AppState>>,
+) -> Result<Vec<PathBuf>, AppError> {
+    let (hm, filter, outinfo) = snapshot(&state).await?;
+
+    let conflicts = tauri::async_runtime::spawn_blocking(move || {
+        let imgs = filter.filter_images(&hm);
+        Plan::new(&imgs, &hm, &outinfo).conflicts()
+    })
+    .await
+    .map_err(anyhow::Error::from)?;
+    Ok(conflicts)
+}
@@ -0,0 +1,408 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Condvar, Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use tauri::ipc::Channel;
+
+use super::{
+    disk::{DiskMonitor, MIN_FREE_BYTES},
+    DLEvent, FieldDone, PlaneDone,
+};
+use crate::parse_xml::{ChannelID, Image};
+
+/// Snapshot of how far along a download is
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Progress {
+    pub images: usize,
+    pub total_images: usize,
+    pub outputs: usize,
+    pub total_outputs: usize,
+    pub bytes: u64,
+    pub bytes_per_sec: f64,
+    /// Estimated seconds until finished, once there's enough to go on
+    pub eta_secs: Option<f64>,
+}
+
+/// What a download is currently doing
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Phase {
+    /// Working out which images to download
+    Planning,
+    Downloading,
+    /// Images already being downloaded are finished, but no new ones are started
+    Paused,
+    Finished,
+    Failed,
+}
+
+/// Where the events of a download go
+pub enum Events {
+    /// The frontend
+    Channel(Channel<DLEvent>),
+    /// Only the progress updates, for running downloads without the frontend
+    Progress(Box<dyn Fn(Progress) + Send + Sync>),
+}
+
+impl Events {
+    fn send(&self, evt: DLEvent) -> Result<()> {
+        match self {
+            Self::Channel(channel) => channel.send(evt).context("sending download progress"),
+            Self::Progress(f) => {
+                if let DLEvent::Progress { progress, .. } = evt {
+                    f(progress);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<Channel<DLEvent>> for Events {
+    fn from(channel: Channel<DLEvent>) -> Self {
+        Self::Channel(channel)
+    }
+}
+
+/// Events that only send the progress updates of a download on to `f`
+pub fn progress_events(f: impl Fn(Progress) + Send + Sync + 'static) -> Events {
+    Events::Progress(Box::new(f))
+}
+
+/// Why a download was paused
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PauseReason {
+    User,
+    /// Resumed by itself once there's enough space again
+    LowDiskSpace,
+}
+
+/// When a download was paused, and for how long, which doesn't count towards
+/// its throughput or ETA
+#[derive(Default)]
+struct Pauses {
+    /// Why the download is paused and since when, while it is
+    current: Option<(PauseReason, Instant)>,
+    /// Time spent in earlier pauses
+    total: Duration,
+}
+
+impl Pauses {
+    fn elapsed(&self) -> Duration {
+        self.total + self.current.map(|(_, t)| t.elapsed()).unwrap_or_default()
+    }
+}
+
+/// What has been done since the last progress update
+#[derive(Default)]
+struct Batch {
+    planes: Vec<PlaneDone>,
+    projected: Vec<FieldDone>,
+    written: Vec<PathBuf>,
+}
+
+/// Keeps count of a running download and sends events about it. Finished
+/// images, projections and outputs are collected and sent along with the
+/// progress updates, which only go out every so often, so large plates don't
+/// flood the IPC channel.
+pub struct Tracker {
+    events: Events,
+    total_images: AtomicUsize,
+    total_outputs: AtomicUsize,
+    images: AtomicUsize,
+    outputs: AtomicUsize,
+    bytes: AtomicU64,
+    start: Instant,
+    paused: Mutex<Pauses>,
+    last_report: Mutex<Instant>,
+    batch: Mutex<Batch>,
+    /// Images done in each well
+    wells: Mutex<BTreeMap<(u16, u16), usize>>,
+    phase: Mutex<Phase>,
+    resumed: Condvar,
+    errors: Mutex<Vec<String>>,
+    /// The output drive, and the disk space the outputs were planned to need
+    disk: OnceLock<(DiskMonitor, u64)>,
+}
+
+impl Tracker {
+    const REPORT_INTERVAL: Duration = Duration::from_millis(250);
+
+    pub fn new(events: Events) -> Self {
+        let now = Instant::now();
+        Self {
+            events,
+            total_images: AtomicUsize::new(0),
+            total_outputs: AtomicUsize::new(0),
+            images: AtomicUsize::new(0),
+            outputs: AtomicUsize::new(0),
+            bytes: AtomicU64::new(0),
+            start: now,
+            paused: Mutex::new(Pauses::default()),
+            last_report: Mutex::new(now),
+            batch: Mutex::new(Batch::default()),
+            wells: Mutex::new(BTreeMap::new()),
+            phase: Mutex::new(Phase::Planning),
+            resumed: Condvar::new(),
+            errors: Mutex::new(Vec::new()),
+            disk: OnceLock::new(),
+        }
+    }
+
+    fn send(&self, evt: DLEvent) -> Result<()> {
+        self.events.send(evt)
+    }
+
+    fn pauses(&self) -> std::sync::MutexGuard<'_, Pauses> {
+        self.paused.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn batch(&self) -> std::sync::MutexGuard<'_, Batch> {
+        self.batch.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn set_phase(&self, phase: Phase) {
+        *self.phase.lock().unwrap_or_else(|e| e.into_inner()) = phase;
+    }
+
+    /// `images` source images will be processed into `outputs` files
+    pub fn started(&self, images: usize, outputs: usize) -> Result<()> {
+        self.total_images.store(images, Ordering::Relaxed);
+        self.total_outputs.store(outputs, Ordering::Relaxed);
+        self.set_phase(Phase::Downloading);
+
+        self.send(DLEvent::Started { images, outputs })
+    }
+
+    /// A source image of `bytes` size was downloaded and processed
+    pub fn image_done(&self, img: &Image, bytes: usize) -> Result<()> {
+        self.images.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+
+        *self
+            .wells
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry((img.row, img.col))
+            .or_default() += 1;
+        self.batch().planes.push(PlaneDone::from(img));
+        self.report(false)
+    }
+
+    /// Every plane of a field has been projected
+    pub fn projected(&self, r: u16, c: u16, t: u32, f: u32, ch: ChannelID) -> Result<()> {
+        self.batch().projected.push(FieldDone { r, c, f, t, ch });
+        self.report(false)
+    }
+
+    /// An output file was written
+    pub fn written(&self, path: &Path) -> Result<()> {
+        self.outputs.fetch_add(1, Ordering::Relaxed);
+
+        self.batch().written.push(path.to_path_buf());
+        self.report(false)
+    }
+
+    pub fn finished(&self) -> Result<()> {
+        self.set_phase(Phase::Finished);
+        self.report(true)?;
+        self.send(DLEvent::Finished)
+    }
+
+    /// The download stopped because of `err`
+    pub fn failed(&self, err: &anyhow::Error) {
+        self.set_phase(Phase::Failed);
+        self.errors
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(format!("{:#}", err));
+    }
+
+    /// Stop starting on new images until `resume` is called
+    pub fn pause(&self, reason: PauseReason) -> Result<()> {
+        {
+            let mut phase = self.phase.lock().unwrap_or_else(|e| e.into_inner());
+            if *phase != Phase::Downloading {
+                bail!("Can't pause a download that is {:?}", *phase);
+            }
+            *phase = Phase::Paused;
+            self.pauses().current = Some((reason, Instant::now()));
+        }
+        tracing::info!(?reason, "paused export");
+        self.send(DLEvent::Paused { reason })
+    }
+
+    pub fn resume(&self) -> Result<()> {
+        self.resume_from(None)
+    }
+
+    /// Resume a download paused for `reason`, or for any reason if it isn't given
+    fn resume_from(&self, reason: Option<PauseReason>) -> Result<()> {
+        {
+            let mut phase = self.phase.lock().unwrap_or_else(|e| e.into_inner());
+            if *phase != Phase::Paused {
+                bail!("Can't resume a download that is {:?}", *phase);
+            }
+
+            let mut pauses = self.pauses();
+            if let (Some(reason), Some((paused_for, _))) = (reason, pauses.current) {
+                if reason != paused_for {
+                    bail!("Can't resume a download paused for {:?}", paused_for);
+                }
+            }
+            *phase = Phase::Downloading;
+            if let Some((_, since)) = pauses.current.take() {
+                pauses.total += since.elapsed();
+            }
+        }
+        self.resumed.notify_all();
+        tracing::info!("resumed export");
+        self.send(DLEvent::Resumed)
+    }
+
+    /// Pause the download while the drive `disk` looks at doesn't have room
+    /// for the rest of the `planned` bytes of outputs
+    pub fn watch_disk(&self, disk: DiskMonitor, planned: u64) {
+        let _ = self.disk.set((disk, planned));
+    }
+
+    /// Disk space the outputs that are still to be written need, going by
+    /// the `planned` space for all of them
+    fn disk_needed(&self, planned: u64) -> u64 {
+        let total = self.total_outputs.load(Ordering::Relaxed);
+        let left = total.saturating_sub(self.outputs.load(Ordering::Relaxed));
+        match total {
+            0 => 0,
+            _ => (planned as f64 * left as f64 / total as f64) as u64,
+        }
+    }
+
+    /// Pause while free space is low, until there is enough again
+    fn check_disk(&self, disk: &DiskMonitor, planned: u64) {
+        let required = self.disk_needed(planned) + MIN_FREE_BYTES;
+        let Some(available) = disk.low_space(required) else {
+            return;
+        };
+
+        tracing::warn!(available, required, "low on disk space, pausing export");
+        let _ = self.send(DLEvent::LowDiskSpace {
+            available,
+            required,
+        });
+        // already paused, by the user or another thread that ran into this
+        if self.pause(PauseReason::LowDiskSpace).is_err() {
+            return;
+        }
+
+        while disk
+            .low_space_now(self.disk_needed(planned) + MIN_FREE_BYTES)
+            .is_some()
+        {
+            thread::sleep(DiskMonitor::LOW_RECHECK);
+        }
+        // unless the user already did, or has paused it since
+        let _ = self.resume_from(Some(PauseReason::LowDiskSpace));
+    }
+
+    /// Block while the download is paused. Call this before starting on an
+    /// image, so that ones already in flight are finished off.
+    pub fn wait_if_paused(&self) {
+        if let Some((disk, planned)) = self.disk.get() {
+            self.check_disk(disk, *planned);
+        }
+
+        let phase = self.phase.lock().unwrap_or_else(|e| e.into_inner());
+        let _phase = self
+            .resumed
+            .wait_while(phase, |p| *p == Phase::Paused)
+            .unwrap_or_else(|e| e.into_inner());
+    }
+
+    /// Why the download is paused, if it is
+    pub fn pause_reason(&self) -> Option<PauseReason> {
+        self.pauses().current.map(|(reason, _)| reason)
+    }
+
+    pub fn phase(&self) -> Phase {
+        *self.phase.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn errors(&self) -> Vec<String> {
+        self.errors
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// `[r, c, images]` done in each well that has been started on
+    pub fn wells(&self) -> Vec<(u16, u16, usize)> {
+        self.wells
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(&(r, c), &n)| (r, c, n))
+            .collect()
+    }
+
+    pub fn progress(&self) -> Progress {
+        let images = self.images.load(Ordering::Relaxed);
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let secs = self
+            .start
+            .elapsed()
+            .saturating_sub(self.pauses().elapsed())
+            .as_secs_f64();
+        let total_images = self.total_images.load(Ordering::Relaxed);
+
+        let eta_secs = match images {
+            0 => None,
+            n => Some(secs / n as f64 * total_images.saturating_sub(n) as f64),
+        };
+
+        Progress {
+            images,
+            total_images,
+            outputs: self.outputs.load(Ordering::Relaxed),
+            total_outputs: self.total_outputs.load(Ordering::Relaxed),
+            bytes,
+            bytes_per_sec: if secs > 0.0 { bytes as f64 / secs } else { 0.0 },
+            eta_secs,
+        }
+    }
+
+    /// Send a progress update with everything done since the last one, if
+    /// it's been long enough since then
+    fn report(&self, force: bool) -> Result<()> {
+        let mut last = match force {
+            true => self.last_report.lock().unwrap_or_else(|e| e.into_inner()),
+            // another thread is already reporting
+            false => match self.last_report.try_lock() {
+                Ok(last) => last,
+                Err(_) => return Ok(()),
+            },
+        };
+
+        if !force && last.elapsed() < Self::REPORT_INTERVAL {
+            return Ok(());
+        }
+        *last = Instant::now();
+
+        let batch = std::mem::take(&mut *self.batch());
+        self.send(DLEvent::Progress {
+            progress: self.progress(),
+            planes: batch.planes,
+            projected: batch.projected,
+            written: batch.written,
+        })
+    }
+}
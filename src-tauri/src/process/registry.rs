@@ -0,0 +1,143 @@
+//! Downloads started from the app. Each runs on its own blocking thread with a
+//! snapshot of the state it needs, so `AppState` isn't locked while images are
+//! downloading, and has a handle kept here so the frontend can ask how it's
+//! going, e.g. after the page was reloaded and its event channel was lost.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tauri::{async_runtime::Mutex, ipc::Channel, State};
+
+use super::{
+    progress::{PauseReason, Phase, Tracker},
+    DLEvent, Progress,
+};
+use crate::error::AppError;
+
+/// A download, as reported by `get_job_status`
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadStatus {
+    pub id: u64,
+    pub outdir: PathBuf,
+    pub phase: Phase,
+    /// Why the download is paused, if it is
+    pub pause_reason: Option<PauseReason>,
+    pub progress: Progress,
+    /// `[r, c, images]` done so far in each well that has been started on
+    pub wells: Vec<(u16, u16, usize)>,
+    /// Errors hit so far; a download stops at its first one
+    pub errors: Vec<String>,
+}
+
+/// A download started from the app. Its worker and the registry share the
+/// tracker, so the status is counted from the same calls the events are sent from.
+#[derive(Clone)]
+pub struct DownloadHandle {
+    pub id: u64,
+    outdir: PathBuf,
+    tracker: Arc<Tracker>,
+}
+
+impl DownloadHandle {
+    pub fn tracker(&self) -> Arc<Tracker> {
+        self.tracker.clone()
+    }
+
+    pub fn status(&self) -> DownloadStatus {
+        DownloadStatus {
+            id: self.id,
+            outdir: self.outdir.clone(),
+            phase: self.tracker.phase(),
+            pause_reason: self.tracker.pause_reason(),
+            progress: self.tracker.progress(),
+            wells: self.tracker.wells(),
+            errors: self.tracker.errors(),
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        matches!(
+            self.tracker.phase(),
+            Phase::Planning | Phase::Downloading | Phase::Paused
+        )
+    }
+}
+
+/// Every download started since the app was opened
+#[derive(Default)]
+pub struct JobRegistry {
+    next_id: u64,
+    jobs: HashMap<u64, DownloadHandle>,
+}
+
+impl JobRegistry {
+    /// Register a download into `outdir`, unless one is already running. Its
+    /// tracker sends events to `channel` and keeps count for `get_job_status`.
+    pub fn start(
+        &mut self,
+        outdir: &Path,
+        channel: Channel<DLEvent>,
+    ) -> Result<DownloadHandle, AppError> {
+        if self.jobs.values().any(DownloadHandle::is_running) {
+            return Err(AppError::other("A download is already running"));
+        }
+
+        let handle = DownloadHandle {
+            id: self.next_id,
+            outdir: outdir.to_path_buf(),
+            tracker: Arc::new(Tracker::new(channel.into())),
+        };
+        self.next_id += 1;
+
+        self.jobs.insert(handle.id, handle.clone());
+        Ok(handle)
+    }
+
+    /// The download `id`, or the latest one if it isn't given
+    fn get(&self, id: Option<u64>) -> Result<&DownloadHandle, AppError> {
+        let found = match id {
+            Some(id) => self.jobs.get(&id),
+            None => self.jobs.keys().max().and_then(|id| self.jobs.get(id)),
+        };
+        found.ok_or_else(|| match id {
+            Some(id) => AppError::other(format!("No download with id {}", id)),
+            None => AppError::other("No download has been started"),
+        })
+    }
+}
+
+/// Status of the download `id`, or of the latest one if it isn't given
+#[tauri::command]
+pub async fn get_job_status(
+    id: Option<u64>,
+    registry: State<'_, Mutex<JobRegistry>>,
+) -> Result<DownloadStatus, AppError> {
+    Ok(registry.lock().await.get(id)?.status())
+}
+
+/// Pause the download `id`, or the latest one. Images already being downloaded
+/// are finished and projections keep what they have so far.
+#[tauri::command]
+pub async fn pause_download(
+    id: Option<u64>,
+    registry: State<'_, Mutex<JobRegistry>>,
+) -> Result<(), AppError> {
+    Ok(registry
+        .lock()
+        .await
+        .get(id)?
+        .tracker
+        .pause(PauseReason::User)?)
+}
+
+#[tauri::command]
+pub async fn resume_download(
+    id: Option<u64>,
+    registry: State<'_, Mutex<JobRegistry>>,
+) -> Result<(), AppError> {
+    Ok(registry.lock().await.get(id)?.tracker.resume()?)
+}
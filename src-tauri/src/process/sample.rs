@@ -0,0 +1,105 @@
+//! Pick a subset of the fields in each well, for quick looks at a plate
+
+use std::collections::{BTreeMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::parse_xml::{ChannelID, Image};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", tag = "strategy")]
+pub enum FieldSampling {
+    /// `count` random fields per well. The same seed always picks the same fields.
+    Random { count: usize, seed: u64 },
+    /// The `count` fields closest to the center of the imaged area of each well
+    Center { count: usize },
+    /// `count` fields spread evenly through the field IDs of each well
+    Evenly { count: usize },
+}
+
+impl FieldSampling {
+    /// Keep only the images from the sampled fields of each well. Each
+    /// channel is sampled on its own, so that one narrowed down to other
+    /// fields (`channel_fields`) isn't left out; as the picks only depend on
+    /// the well and its fields, channels with the same fields get the same ones.
+    pub fn apply<'a>(&self, imgs: Vec<&'a Image>) -> Vec<&'a Image> {
+        // (row, col, channel) -> field -> (x, y) position
+        let mut wells: BTreeMap<(u16, u16, ChannelID), BTreeMap<u32, (f64, f64)>> = BTreeMap::new();
+        for img in imgs.iter() {
+            wells
+                .entry((img.row, img.col, img.channel))
+                .or_default()
+                .entry(img.field)
+                .or_insert((img.position[0], img.position[1]));
+        }
+
+        let keep: HashSet<(u16, u16, ChannelID, u32)> = wells
+            .into_iter()
+            .flat_map(|((r, c, ch), fields)| {
+                let fields: Vec<_> = fields.into_iter().collect();
+                self.pick(r, c, fields)
+                    .into_iter()
+                    .map(move |f| (r, c, ch, f))
+            })
+            .collect();
+
+        imgs.into_iter()
+            .filter(|img| keep.contains(&(img.row, img.col, img.channel, img.field)))
+            .collect()
+    }
+
+    /// Choose fields from one well. `fields` is sorted by field ID.
+    fn pick(&self, row: u16, col: u16, fields: Vec<(u32, (f64, f64))>) -> Vec<u32> {
+        match *self {
+            Self::Random { count, seed } => {
+                // seed per well so that each well gets its own draw
+                let mut rng = SplitMix64(seed ^ (((row as u64) << 32) | col as u64));
+                let mut ids: Vec<u32> = fields.into_iter().map(|(f, _)| f).collect();
+
+                // partial Fisher-Yates shuffle
+                let n = count.min(ids.len());
+                for i in 0..n {
+                    let j = i + (rng.next() % (ids.len() - i) as u64) as usize;
+                    ids.swap(i, j);
+                }
+                ids.truncate(n);
+                ids
+            }
+            Self::Center { count } => {
+                let n = fields.len().max(1) as f64;
+                let (cx, cy) = fields
+                    .iter()
+                    .fold((0.0, 0.0), |(x, y), (_, (fx, fy))| (x + fx / n, y + fy / n));
+
+                let mut by_dist: Vec<(f64, u32)> = fields
+                    .into_iter()
+                    .map(|(f, (x, y))| ((x - cx).powi(2) + (y - cy).powi(2), f))
+                    .collect();
+                by_dist.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+                by_dist.into_iter().take(count).map(|(_, f)| f).collect()
+            }
+            Self::Evenly { count } => {
+                let n = fields.len();
+                let count = count.min(n);
+                // middle of each of `count` equal chunks
+                (0..count)
+                    .map(|i| fields[(2 * i + 1) * n / (2 * count)].0)
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Small, fixed PRNG so that a seed picks the same fields on every version
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
@@ -0,0 +1,194 @@
+//! Optional checks on downloaded images, so that truncated files or error
+//! pages don't end up saved as images. Checked images are recorded, with a
+//! checksum, in a manifest in the output folder once their output is written.
+
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::Cursor,
+    path::Path,
+    sync::Mutex,
+};
+
+use anyhow::{bail, Context, Result};
+use image::{DynamicImage, ImageFormat, ImageReader};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::PathContext,
+    parse_xml::{Harmony, Image},
+};
+
+/// File name of the manifest, written into the output folder
+pub const MANIFEST_NAME: &str = "harmony-dl-manifest.csv";
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct Verification {
+    /// Times to download an image again if it fails the checks
+    pub retries: u32,
+}
+
+#[derive(serde::Serialize)]
+struct ManifestRow<'a> {
+    url: &'a str,
+    output: &'a Path,
+    bytes: usize,
+    sha256: &'a str,
+}
+
+/// A checked image, to be recorded in the manifest once its output is written
+pub struct Checked {
+    url: String,
+    bytes: usize,
+    sha256: String,
+}
+
+/// A downloaded image
+pub struct Fetched {
+    pub raw: Vec<u8>,
+    /// The image, if it was already decoded to check it
+    pub decoded: Option<DynamicImage>,
+    pub checked: Option<Checked>,
+}
+
+/// Fetches images for an export, checking them if it was asked for
+pub struct Fetcher<'a> {
+    hm: &'a Harmony,
+    verify: Option<Verification>,
+    manifest: Option<Mutex<csv::Writer<File>>>,
+}
+
+impl<'a> Fetcher<'a> {
+    pub fn new(hm: &'a Harmony, verify: Option<Verification>, outdir: &Path) -> Result<Self> {
+        let manifest = match verify {
+            Some(_) => {
+                let path = outdir.join(MANIFEST_NAME);
+                // added to, so outputs of earlier runs that are kept, e.g.
+                // skipped ones, keep their checksums
+                let f = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .with_context(|| PathContext::new("opening manifest", &path))?;
+                let is_new = f
+                    .metadata()
+                    .with_context(|| PathContext::new("opening manifest", &path))?
+                    .len()
+                    == 0;
+                let writer = csv::WriterBuilder::new().has_headers(is_new).from_writer(f);
+                Some(Mutex::new(writer))
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            hm,
+            verify,
+            manifest,
+        })
+    }
+
+    /// Get the raw bytes of `img`, checking them if it was asked for
+    pub fn fetch(&self, img: &Image) -> Result<Fetched> {
+        let Some(verify) = self.verify else {
+            return Ok(Fetched {
+                raw: self.hm.source.fetch(&img.url)?,
+                decoded: None,
+                checked: None,
+            });
+        };
+
+        let mut tries = 0;
+        let (raw, decoded) = loop {
+            let res = self
+                .hm
+                .source
+                .fetch(&img.url)
+                .and_then(|raw| self.check(img, &raw).map(|decoded| (raw, decoded)));
+
+            match res {
+                Ok(fetched) => break fetched,
+                Err(e) if tries < verify.retries => {
+                    tries += 1;
+                    tracing::warn!(url = img.url.as_str(), tries, error = ?e, "image failed checks, retrying");
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        let checked = self.manifest.as_ref().map(|_| Checked {
+            url: img.url.clone(),
+            bytes: raw.len(),
+            sha256: sha256_hex(&raw),
+        });
+        Ok(Fetched {
+            raw,
+            decoded: Some(decoded),
+            checked,
+        })
+    }
+
+    /// Check that `raw` is a whole TIFF image of the size given for its channel
+    fn check(&self, img: &Image, raw: &[u8]) -> Result<DynamicImage> {
+        let decoded = ImageReader::with_format(Cursor::new(raw), ImageFormat::Tiff)
+            .decode()
+            .with_context(|| format!("checking <{}> is a TIFF image", &img.url))?;
+
+        let actual = (decoded.width(), decoded.height());
+        match self.hm.channels.get(&img.channel).and_then(|ch| ch.size) {
+            Some(expected) if expected != actual => bail!(
+                "Image <{}> is {}x{}, but its channel has {}x{} images",
+                &img.url,
+                actual.0,
+                actual.1,
+                expected.0,
+                expected.1
+            ),
+            _ => Ok(decoded),
+        }
+    }
+
+    /// Record the `checked` images that went into `output`, now that it's been written
+    pub fn record<'c>(
+        &self,
+        checked: impl IntoIterator<Item = &'c Checked>,
+        output: &Path,
+    ) -> Result<()> {
+        let Some(ref manifest) = self.manifest else {
+            return Ok(());
+        };
+
+        let mut manifest = manifest.lock().unwrap_or_else(|e| e.into_inner());
+        for c in checked {
+            let row = ManifestRow {
+                url: &c.url,
+                output,
+                bytes: c.bytes,
+                sha256: &c.sha256,
+            };
+            manifest.serialize(row).context("writing manifest")?;
+        }
+        Ok(())
+    }
+
+    /// Make sure everything recorded has been written to the manifest
+    pub fn finish(&self) -> Result<()> {
+        match self.manifest {
+            Some(ref m) => m
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .flush()
+                .context("writing manifest"),
+            None => Ok(()),
+        }
+    }
+}
+
+fn sha256_hex(raw: &[u8]) -> String {
+    Sha256::digest(raw)
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, b| {
+            let _ = write!(hex, "{:02x}", b);
+            hex
+        })
+}
@@ -0,0 +1,351 @@
+//! Queue of export jobs, for processing a whole campaign of plates in one go.
+//! The queue is saved to the app data dir after every change, so it survives
+//! restarts; jobs that were running when the app closed are marked as failed,
+//! so that they can be looked at and retried.
+
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, ErrorKind},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    thread,
+};
+
+use anyhow::{anyhow, Context, Result};
+use tauri::{async_runtime::Mutex, ipc::Channel, AppHandle, Manager, State};
+
+use crate::{
+    error::{AppError, PathContext},
+    job::ExportJob,
+    process::{atomic, progress_events, run_export},
+};
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", tag = "status", content = "data")]
+pub enum JobStatus {
+    Queued,
+    Running { processed: usize },
+    Finished,
+    Failed { error: String },
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct QueuedJob {
+    pub id: u64,
+    pub job: ExportJob,
+    pub status: JobStatus,
+}
+
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+pub struct JobQueue {
+    next_id: u64,
+    jobs: Vec<QueuedJob>,
+    // where the queue is saved
+    #[serde(skip)]
+    file: Option<PathBuf>,
+    #[serde(skip)]
+    running: bool,
+    // why the saved queue couldn't be read, if it couldn't
+    #[serde(skip)]
+    load_error: Option<String>,
+}
+
+impl JobQueue {
+    /// Read the saved queue from `file`, starting a new one if there isn't one
+    pub fn load(file: Option<PathBuf>) -> Result<Self> {
+        let mut queue = match file {
+            Some(ref path) => Self::read(path)?.unwrap_or_default(),
+            None => Self::default(),
+        };
+
+        for q in queue.jobs.iter_mut() {
+            if let JobStatus::Running { .. } = q.status {
+                q.status = JobStatus::Failed {
+                    error: "Interrupted, the app closed while the job was running".to_string(),
+                };
+            }
+        }
+        queue.file = file;
+        Ok(queue)
+    }
+
+    fn read(path: &Path) -> Result<Option<Self>> {
+        let f = match File::open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| PathContext::new("opening queue", path)),
+        };
+        serde_json::from_reader(BufReader::new(f))
+            .map(Some)
+            .with_context(|| format!("reading queue <{}>", path.display()))
+    }
+
+    /// Start a new queue in place of the unreadable one in `file`, which is
+    /// kept next to it rather than overwritten
+    pub fn replace_broken(file: Option<PathBuf>, error: &anyhow::Error) -> Self {
+        if let Some(ref path) = file {
+            let backup = path.with_extension("json.broken");
+            tracing::error!(error = ?error, backup = %backup.display(), "couldn't read the job queue, starting a new one");
+            if let Err(e) = fs::rename(path, &backup) {
+                tracing::error!(error = %e, "couldn't keep the unreadable job queue");
+            }
+        }
+
+        Self {
+            file,
+            load_error: Some(format!("{:#}", error)),
+            ..Default::default()
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        let Some(ref path) = self.file else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).context("creating app data dir")?;
+        }
+
+        atomic::write(path, |tmp| {
+            let f = File::create(tmp).with_context(|| PathContext::new("creating queue", tmp))?;
+            let mut w = BufWriter::new(f);
+            serde_json::to_writer(&mut w, self).context("writing queue file")?;
+            w.into_inner()
+                .map_err(|e| e.into_error())
+                .and_then(|f| f.sync_all())
+                .with_context(|| PathContext::new("writing queue", tmp))
+        })
+    }
+
+    fn add(&mut self, job: ExportJob) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.jobs.push(QueuedJob {
+            id,
+            job,
+            status: JobStatus::Queued,
+        });
+        id
+    }
+
+    /// Take the next waiting job, marking it as running
+    fn start_next(&mut self) -> Option<(u64, ExportJob)> {
+        let q = self
+            .jobs
+            .iter_mut()
+            .find(|q| matches!(q.status, JobStatus::Queued))?;
+        q.status = JobStatus::Running { processed: 0 };
+
+        let next = (q.id, q.job.clone());
+        let _ = self.save();
+        Some(next)
+    }
+
+    fn set_status(&mut self, id: u64, status: JobStatus) {
+        // progress updates are frequent, so only save on a real change
+        let changed = !matches!(status, JobStatus::Running { .. });
+
+        if let Some(q) = self.jobs.iter_mut().find(|q| q.id == id) {
+            q.status = status;
+        }
+        if changed {
+            let _ = self.save();
+        }
+    }
+
+    fn status_of(&self, id: u64) -> Option<&JobStatus> {
+        self.jobs.iter().find(|q| q.id == id).map(|q| &q.status)
+    }
+}
+
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase", tag = "event", content = "data")]
+pub enum QueueEvent {
+    JobStarted {
+        id: u64,
+    },
+    JobProgress {
+        id: u64,
+        processed: usize,
+        total: usize,
+    },
+    JobFinished {
+        id: u64,
+    },
+    JobFailed {
+        id: u64,
+        error: String,
+    },
+    Finished,
+}
+
+/// Run one job from the queue, keeping its status up to date
+fn run_queued(
+    app: &AppHandle,
+    id: u64,
+    job: &ExportJob,
+    on_event: &Channel<QueueEvent>,
+) -> Result<()> {
+    let cache_dir = app.path().app_cache_dir().ok();
+    let hm = job.load_export(cache_dir.as_deref())?;
+
+    let progress_app = app.clone();
+    let progress_evt = on_event.clone();
+    let events = progress_events(move |p| {
+        let queue = progress_app.state::<Mutex<JobQueue>>();
+        queue.blocking_lock().set_status(
+            id,
+            JobStatus::Running {
+                processed: p.images,
+            },
+        );
+        let _ = progress_evt.send(QueueEvent::JobProgress {
+            id,
+            processed: p.images,
+            total: p.total_images,
+        });
+    });
+
+    run_export(&hm, &job.filter, &job.output, events)
+}
+
+/// Keep taking jobs off of the queue until there are none left
+fn worker(app: AppHandle, on_event: Channel<QueueEvent>) {
+    let queue = app.state::<Mutex<JobQueue>>();
+
+    loop {
+        let next = queue.blocking_lock().start_next();
+        let Some((id, job)) = next else {
+            break;
+        };
+        let _ = on_event.send(QueueEvent::JobStarted { id });
+
+        // a panic shouldn't leave the job marked as running forever
+        let res = panic::catch_unwind(AssertUnwindSafe(|| run_queued(&app, id, &job, &on_event)))
+            .unwrap_or_else(|e| {
+                let msg = e
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| e.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                Err(anyhow!("Export crashed: {}", msg))
+            });
+
+        let (status, evt) = match res {
+            Ok(()) => (JobStatus::Finished, QueueEvent::JobFinished { id }),
+            Err(e) => {
+                let error = format!("{:?}", e);
+                let evt = QueueEvent::JobFailed {
+                    id,
+                    error: error.clone(),
+                };
+                (JobStatus::Failed { error }, evt)
+            }
+        };
+
+        queue.blocking_lock().set_status(id, status);
+        let _ = on_event.send(evt);
+    }
+}
+
+#[tauri::command]
+pub async fn enqueue_jobs(
+    paths: Vec<PathBuf>,
+    queue: State<'_, Mutex<JobQueue>>,
+) -> Result<Vec<u64>, AppError> {
+    let jobs = paths
+        .iter()
+        .map(|p| ExportJob::load(p))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut queue = queue.lock().await;
+    let ids = jobs.into_iter().map(|job| queue.add(job)).collect();
+    queue.save()?;
+
+    Ok(ids)
+}
+
+#[derive(serde::Serialize)]
+pub struct QueueInfo {
+    jobs: Vec<QueuedJob>,
+    running: bool,
+    /// Why the saved queue couldn't be read when the app started
+    load_error: Option<String>,
+}
+
+#[tauri::command]
+pub async fn get_queue(queue: State<'_, Mutex<JobQueue>>) -> Result<QueueInfo, AppError> {
+    let queue = queue.lock().await;
+    Ok(QueueInfo {
+        jobs: queue.jobs.clone(),
+        running: queue.running,
+        load_error: queue.load_error.clone(),
+    })
+}
+
+/// Queue a failed job again
+#[tauri::command]
+pub async fn retry_job(id: u64, queue: State<'_, Mutex<JobQueue>>) -> Result<(), AppError> {
+    let mut queue = queue.lock().await;
+
+    match queue.status_of(id) {
+        Some(JobStatus::Failed { .. }) => (),
+        Some(_) => return Err(AppError::other("Only failed jobs can be retried")),
+        None => return Err(AppError::other(format!("No job with id {}", id))),
+    }
+    if let Some(q) = queue.jobs.iter_mut().find(|q| q.id == id) {
+        q.job.retrying();
+    }
+    queue.set_status(id, JobStatus::Queued);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn remove_job(id: u64, queue: State<'_, Mutex<JobQueue>>) -> Result<(), AppError> {
+    let mut queue = queue.lock().await;
+
+    match queue.status_of(id) {
+        Some(JobStatus::Running { .. }) => {
+            return Err(AppError::other("Can't remove a running job"))
+        }
+        None => return Err(AppError::other(format!("No job with id {}", id))),
+        _ => (),
+    }
+    queue.jobs.retain(|q| q.id != id);
+    Ok(queue.save()?)
+}
+
+/// Start working through the queue with up to `parallel` jobs at once. This
+/// returns right away; progress is reported through `on_event`.
+#[tauri::command]
+pub async fn run_queue(
+    parallel: usize,
+    on_event: Channel<QueueEvent>,
+    app: AppHandle,
+    queue: State<'_, Mutex<JobQueue>>,
+) -> Result<(), AppError> {
+    {
+        let mut queue = queue.lock().await;
+        if queue.running {
+            return Err(AppError::other("Queue is already running"));
+        }
+        queue.running = true;
+    }
+
+    thread::spawn(move || {
+        let workers: Vec<_> = (0..parallel.max(1))
+            .map(|_| {
+                let (app, evt) = (app.clone(), on_event.clone());
+                thread::spawn(move || worker(app, evt))
+            })
+            .collect();
+        for w in workers {
+            let _ = w.join();
+        }
+
+        app.state::<Mutex<JobQueue>>().blocking_lock().running = false;
+        let _ = on_event.send(QueueEvent::Finished);
+    });
+
+    Ok(())
+}
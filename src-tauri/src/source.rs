@@ -0,0 +1,514 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Instant,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+use crate::{
+    error::{AppError, PathContext},
+    net,
+    process::disk,
+};
+
+/// Names Harmony uses for the index file, in order of preference. The last
+/// two can't be read, but are found so that the error says why.
+pub const INDEX_NAMES: [&str; 4] = [
+    "Index.idx.xml",
+    "Index.xml",
+    "Index.ref.xml",
+    "Index.flex.xml",
+];
+
+/// Where an export is read from. Images referenced by a relative path in the
+/// index are resolved against the folder the index is in, whether that is on
+/// disk or inside an archive.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub enum ExportSource {
+    /// An index XML file on disk
+    File(PathBuf),
+    /// A zip archive, with `index` being the member name of the index XML
+    Zip {
+        archive: PathBuf,
+        index: String,
+        #[serde(skip)]
+        opened: OpenedArchive,
+    },
+    /// A gzipped tarball. This can't be seeked, so the first image read
+    /// decompresses it into a temp file in the spool folder (see
+    /// `configure_spool_dir`), which needs as much free space as the uncompressed
+    /// export; prefer zip for large exports.
+    TarGz {
+        archive: PathBuf,
+        index: String,
+        #[serde(skip)]
+        opened: OpenedArchive,
+    },
+}
+
+impl ExportSource {
+    /// Open the index file at `path`, which can be an XML file or an archive
+    /// containing one, and hand it, along with its size, to `parse`
+    pub fn open<T>(
+        path: &Path,
+        parse: impl FnOnce(Self, &mut dyn Read, u64) -> Result<T>,
+    ) -> Result<T> {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        if name.ends_with(".zip") {
+            Self::open_zip(path, parse)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Self::open_tar_gz(path, parse)
+        } else {
+            let mut f =
+                File::open(path).with_context(|| PathContext::new("opening XML file", path))?;
+            let total = f.metadata().map(|m| m.len()).unwrap_or(0);
+
+            parse(Self::File(path.to_path_buf()), &mut f, total)
+        }
+    }
+
+    fn open_zip<T>(
+        path: &Path,
+        parse: impl FnOnce(Self, &mut dyn Read, u64) -> Result<T>,
+    ) -> Result<T> {
+        let mut zip = open_zip(path)?;
+
+        let index = find_index(zip.file_names())
+            .ok_or_else(|| anyhow!("No index XML file in <{}>", path.display()))?;
+        let mut member = zip
+            .by_name(&index)
+            .with_context(|| format!("opening <{}> in zip archive", &index))?;
+        let total = member.size();
+
+        let source = Self::Zip {
+            archive: path.to_path_buf(),
+            index,
+            opened: OpenedArchive::default(),
+        };
+        parse(source, &mut member, total)
+    }
+
+    fn open_tar_gz<T>(
+        path: &Path,
+        parse: impl FnOnce(Self, &mut dyn Read, u64) -> Result<T>,
+    ) -> Result<T> {
+        // the stream can't be rewound, so find the best index first, then
+        // read through again to get to it
+        let mut names = Vec::new();
+        for entry in open_tar_gz(path)?
+            .entries()
+            .context("reading tar entries")?
+        {
+            let name = entry_name(&entry.context("reading tar entry")?)?;
+            if find_index(std::iter::once(name.as_str())).is_some() {
+                names.push(name);
+            }
+        }
+        let index = find_index(names.iter().map(String::as_str))
+            .ok_or_else(|| anyhow!("No index XML file in <{}>", path.display()))?;
+
+        let mut tar = open_tar_gz(path)?;
+        for entry in tar.entries().context("reading tar entries")? {
+            let mut entry = entry.context("reading tar entry")?;
+            if entry_name(&entry)? == index {
+                let total = entry.header().size().unwrap_or(0);
+                let source = Self::TarGz {
+                    archive: path.to_path_buf(),
+                    index,
+                    opened: OpenedArchive::default(),
+                };
+                return parse(source, &mut entry, total);
+            }
+        }
+
+        bail!("<{}> changed while it was being read", path.display())
+    }
+
+    /// The index file or archive that was opened
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::File(p) => p,
+            Self::Zip { archive, .. } | Self::TarGz { archive, .. } => archive,
+        }
+    }
+
+    /// File name of the index, without the folders it's in
+    pub fn index_name(&self) -> &str {
+        let index = match self {
+            Self::File(p) => return p.file_name().and_then(|n| n.to_str()).unwrap_or(""),
+            Self::Zip { index, .. } | Self::TarGz { index, .. } => index,
+        };
+        index.rsplit('/').next().unwrap_or(index)
+    }
+
+    /// Get the raw bytes of an image from its URL in the index. This is either
+    /// a web address on the Harmony server or a path relative to the index file,
+    /// which may well be on a network share, so both are throttled.
+    pub fn fetch(&self, url: &str) -> Result<Vec<u8>> {
+        net::throttled(|limit| self.fetch_limited(url, limit))
+    }
+
+    fn fetch_limited(&self, url: &str, limit: &net::Limiter) -> Result<Vec<u8>> {
+        let start = Instant::now();
+
+        if url.starts_with("http://") || url.starts_with("https://") {
+            let res = net::get(url).and_then(|res| {
+                // e.g. an HTML error page instead of the image
+                let res = res
+                    .error_for_status()
+                    .with_context(|| format!("downloading image from <{}>", url))?;
+                let status = res.status();
+                let expected = res.content_length();
+                let raw = limit
+                    .read_to_end(res, expected.unwrap_or(0))
+                    .with_context(|| format!("downloading image from <{}>", url))?;
+
+                if let Some(n) = expected.filter(|&n| n != raw.len() as u64) {
+                    bail!("Got {} of {} bytes from <{}>", raw.len(), n, url);
+                }
+                Ok((status, raw))
+            });
+            let ms = start.elapsed().as_millis() as u64;
+
+            match res {
+                Ok((status, raw)) => {
+                    tracing::debug!(
+                        url,
+                        status = status.as_u16(),
+                        bytes = raw.len(),
+                        ms,
+                        "fetched image"
+                    );
+                    Ok(raw)
+                }
+                Err(e) => {
+                    let status = e
+                        .downcast_ref::<reqwest::Error>()
+                        .and_then(|e| e.status())
+                        .map(|s| s.as_u16());
+                    tracing::warn!(url, status, ms, error = %e, "failed to fetch image");
+                    Err(e)
+                }
+            }
+        } else {
+            let res = self.read_image(url, limit);
+            let ms = start.elapsed().as_millis() as u64;
+
+            match res {
+                Ok(ref raw) => tracing::debug!(url, bytes = raw.len(), ms, "read image"),
+                Err(ref e) => tracing::warn!(url, ms, error = %e, "failed to read image"),
+            }
+            res
+        }
+    }
+
+    fn read_image(&self, path: &str, limit: &net::Limiter) -> Result<Vec<u8>> {
+        // exports written on Windows can use backslashes
+        let path = path.replace('\\', "/");
+
+        match self {
+            Self::File(index) => {
+                let dir = index.parent().unwrap_or(Path::new(""));
+                let full = dir.join(&path);
+                File::open(&full)
+                    .and_then(|f| {
+                        let size = f.metadata()?.len();
+                        limit.read_to_end(f, size)
+                    })
+                    .with_context(|| PathContext::new("reading", &full))
+            }
+            Self::Zip {
+                archive,
+                index,
+                opened,
+            } => {
+                let name = member_path(index, &path);
+                // a clone shares the central directory, but reads on its own
+                let mut zip = match *opened.get(|| open_zip(archive).map(Opened::Zip))? {
+                    Opened::Zip(ref zip) => zip.clone(),
+                    Opened::Tar { .. } => unreachable!("zip opened as a tar archive"),
+                };
+                let member = zip
+                    .by_name(&name)
+                    .with_context(|| format!("opening <{}> in zip archive", &name))?;
+
+                let size = member.size();
+                limit
+                    .read_to_end(member, size)
+                    .with_context(|| format!("reading <{}> from zip archive", &name))
+            }
+            Self::TarGz {
+                archive,
+                index,
+                opened,
+            } => {
+                let name = member_path(index, &path);
+                let opened = opened.get(|| spool_tar_gz(archive))?;
+                let Opened::Tar {
+                    ref file,
+                    ref members,
+                    ..
+                } = *opened
+                else {
+                    unreachable!("tar opened as a zip archive")
+                };
+
+                let &(offset, size) = members
+                    .get(&name)
+                    .ok_or_else(|| anyhow!("No member <{}> in <{}>", name, archive.display()))?;
+                let mut member = file.clone();
+                member.seek(SeekFrom::Start(offset))?;
+
+                limit
+                    .read_to_end(member.take(size), size)
+                    .with_context(|| format!("reading <{}> from tar archive", &name))
+            }
+        }
+    }
+}
+
+/// An archive, opened the first time an image is read from it and then kept
+/// for every other image, shared by clones of its `ExportSource`
+#[derive(Clone, Default)]
+pub struct OpenedArchive(Arc<Mutex<Option<Arc<Opened>>>>);
+
+impl OpenedArchive {
+    fn get(&self, open: impl FnOnce() -> Result<Opened>) -> Result<Arc<Opened>> {
+        // other threads wait for the first one to open it, instead of all opening it
+        let mut opened = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        match *opened {
+            Some(ref o) => Ok(o.clone()),
+            None => Ok(opened.insert(Arc::new(open()?)).clone()),
+        }
+    }
+}
+
+impl fmt::Debug for OpenedArchive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let opened = self.0.try_lock().map(|o| o.is_some()).unwrap_or(false);
+        f.debug_tuple("OpenedArchive").field(&opened).finish()
+    }
+}
+
+enum Opened {
+    Zip(ZipArchive<SharedFile>),
+    /// A tarball decompressed into `spool`, with the offset and size of each member
+    Tar {
+        file: SharedFile,
+        members: HashMap<String, (u64, u64)>,
+        spool: PathBuf,
+    },
+}
+
+impl Drop for Opened {
+    fn drop(&mut self) {
+        if let Self::Tar { ref spool, .. } = *self {
+            if let Err(e) = fs::remove_file(spool) {
+                tracing::warn!(path = %spool.display(), error = %e, "failed to remove decompressed archive");
+            }
+        }
+    }
+}
+
+/// A file that can be read from several threads at once, each clone keeping
+/// its own position
+#[derive(Clone)]
+struct SharedFile {
+    file: Arc<File>,
+    len: u64,
+    pos: u64,
+}
+
+impl SharedFile {
+    fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| PathContext::new("opening", path))?;
+        let len = file
+            .metadata()
+            .with_context(|| PathContext::new("opening", path))?
+            .len();
+        Ok(Self {
+            file: Arc::new(file),
+            len,
+            pos: 0,
+        })
+    }
+}
+
+impl Read for SharedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let n = std::os::unix::fs::FileExt::read_at(&*self.file, buf, self.pos)?;
+        #[cfg(windows)]
+        let n = std::os::windows::fs::FileExt::seek_read(&*self.file, buf, self.pos)?;
+
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for SharedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(d) => self.len.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        self.pos = new.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seeking before the start")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+fn open_zip(path: &Path) -> Result<ZipArchive<SharedFile>> {
+    ZipArchive::new(SharedFile::open(path)?).context("reading zip archive")
+}
+
+/// Folder tarballs are decompressed into, if not the system temp folder
+static SPOOL_DIR: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+/// Decompress tarballs into `dir`, or the system temp folder if it's `None`,
+/// e.g. when that is on a drive without room for a whole export
+pub fn configure_spool_dir(dir: Option<PathBuf>) {
+    *SPOOL_DIR.write().unwrap_or_else(|e| e.into_inner()) = dir;
+}
+
+fn spool_dir() -> PathBuf {
+    SPOOL_DIR
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+        .unwrap_or_else(std::env::temp_dir)
+}
+
+/// Decompress the tarball at `path` into a temp file, once, and find where
+/// each member is in it
+fn spool_tar_gz(path: &Path) -> Result<Opened> {
+    static SPOOLED: AtomicU64 = AtomicU64::new(0);
+
+    // the uncompressed size isn't known without decompressing, but images
+    // hardly compress, so the archive's own size is a fair lower bound
+    let dir = spool_dir();
+    let compressed = fs::metadata(path)
+        .with_context(|| PathContext::new("reading metadata of", path))?
+        .len();
+    let free = disk::available(&dir)?;
+    if free < compressed + disk::MIN_FREE_BYTES {
+        bail!(
+            "Not enough space to decompress <{}> into <{}>: it needs over {:.1} GB, but only {:.1} GB is free. Choose another folder to decompress archives into, or export from a zip.",
+            path.display(),
+            dir.display(),
+            compressed as f64 / 1e9,
+            free as f64 / 1e9
+        );
+    }
+
+    let spool = dir.join(format!(
+        "harmony-dl-{}-{}.tar",
+        std::process::id(),
+        SPOOLED.fetch_add(1, Ordering::Relaxed)
+    ));
+    let start = Instant::now();
+    tracing::info!(archive = %path.display(), spool = %spool.display(), "decompressing archive");
+
+    let res = (|| {
+        let f = File::open(path).with_context(|| PathContext::new("opening", path))?;
+        let mut out = BufWriter::new(
+            File::create(&spool).with_context(|| PathContext::new("creating", &spool))?,
+        );
+        io::copy(&mut GzDecoder::new(BufReader::new(f)), &mut out)
+            .with_context(|| PathContext::new("decompressing into", &spool))?;
+        out.into_inner()
+            .map_err(|e| e.into_error())
+            .with_context(|| PathContext::new("decompressing into", &spool))?;
+
+        let mut members = HashMap::new();
+        let f = File::open(&spool).with_context(|| PathContext::new("opening", &spool))?;
+        let mut tar = tar::Archive::new(BufReader::new(f));
+        for entry in tar.entries_with_seek().context("reading tar entries")? {
+            let entry = entry.context("reading tar entry")?;
+            members.insert(
+                entry_name(&entry)?,
+                (entry.raw_file_position(), entry.size()),
+            );
+        }
+
+        Ok(members)
+    })();
+
+    match res {
+        Ok(members) => {
+            tracing::info!(
+                archive = %path.display(),
+                members = members.len(),
+                secs = start.elapsed().as_secs_f64(),
+                "decompressed archive"
+            );
+            Ok(Opened::Tar {
+                file: SharedFile::open(&spool)?,
+                members,
+                spool,
+            })
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&spool);
+            Err(e)
+        }
+    }
+}
+
+fn open_tar_gz(path: &Path) -> Result<tar::Archive<GzDecoder<BufReader<File>>>> {
+    let f = File::open(path).with_context(|| PathContext::new("opening", path))?;
+    Ok(tar::Archive::new(GzDecoder::new(BufReader::new(f))))
+}
+
+fn entry_name<R: Read>(entry: &tar::Entry<'_, R>) -> Result<String> {
+    entry
+        .path()
+        .map(|p| p.to_string_lossy().replace('\\', "/"))
+        .context("reading tar entry path")
+}
+
+/// Find the index XML file among the members of an archive, preferring the
+/// most specific file name and then the shallowest path
+fn find_index<'a>(names: impl Iterator<Item = &'a str>) -> Option<String> {
+    names
+        .filter_map(|name| {
+            let base = name.rsplit('/').next().unwrap_or(name);
+            INDEX_NAMES
+                .iter()
+                .position(|&idx| idx == base)
+                .map(|rank| (rank, name.matches('/').count(), name))
+        })
+        .min()
+        .map(|(_, _, name)| name.to_string())
+}
+
+/// Archive member name of `path`, relative to the folder holding `index`
+fn member_path(index: &str, path: &str) -> String {
+    match index.rsplit_once('/') {
+        Some((dir, _)) => format!("{}/{}", dir, path),
+        None => path.to_string(),
+    }
+}
+
+/// Decompress tarballs into `dir`, or the system temp folder if it's `None`
+#[tauri::command]
+pub async fn set_spool_dir(dir: Option<PathBuf>) -> Result<(), AppError> {
+    configure_spool_dir(dir);
+    Ok(())
+}
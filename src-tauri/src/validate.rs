@@ -0,0 +1,225 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt,
+};
+
+use tauri::{async_runtime::Mutex, State};
+
+use crate::{
+    error::AppError,
+    parse_xml::{ChannelID, Harmony, Image},
+    AppState,
+};
+
+/// Everything in an export that doesn't line up with a complete acquisition
+#[derive(Debug, Default, Clone, serde::Serialize)]
+pub struct ValidationReport {
+    /// Wells that are missing some of the images the rest of the plate has
+    pub incomplete_wells: Vec<IncompleteWell>,
+    /// Images that appear more than once in the index
+    pub duplicates: Vec<ImageRef>,
+    /// Images with a row or column outside of the plate
+    pub out_of_bounds: Vec<ImageRef>,
+    /// Channels that images refer to, but that aren't described in `<Maps>`
+    pub unknown_channels: Vec<ChannelID>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct IncompleteWell {
+    pub row: u16,
+    pub col: u16,
+    pub missing_fields: Vec<u32>,
+    pub missing_planes: Vec<u16>,
+    pub missing_timepoints: Vec<u32>,
+    pub missing_channels: Vec<ChannelID>,
+    /// Number of (field, plane, timepoint, channel) combinations without an image
+    pub missing_images: usize,
+}
+
+/// Identifies a single image in the index
+#[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, serde::Serialize)]
+pub struct ImageRef {
+    pub row: u16,
+    pub col: u16,
+    pub field: u32,
+    pub plane: u16,
+    pub timepoint: u32,
+    pub channel: ChannelID,
+}
+
+impl From<&Image> for ImageRef {
+    fn from(img: &Image) -> Self {
+        Self {
+            row: img.row,
+            col: img.col,
+            field: img.field,
+            plane: img.plane,
+            timepoint: img.timepoint,
+            channel: img.channel,
+        }
+    }
+}
+
+impl fmt::Display for ImageRef {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            row,
+            col,
+            field,
+            plane,
+            timepoint,
+            channel,
+        } = self;
+        write!(
+            fmt,
+            "R{row}C{col}T{timepoint}F{field}P{plane} @ {channel:?}"
+        )
+    }
+}
+
+/// Images found in one well
+#[derive(Default)]
+struct WellImages {
+    fields: BTreeSet<u32>,
+    planes: BTreeSet<u16>,
+    timepoints: BTreeSet<u32>,
+    channels: BTreeSet<ChannelID>,
+    images: HashSet<(u32, u16, u32, ChannelID)>,
+}
+
+impl ValidationReport {
+    pub fn new(hm: &Harmony) -> Self {
+        let mut report = Self::default();
+
+        // everything the plate was acquired with, which every well should have
+        let mut fields = BTreeSet::new();
+        let mut planes = BTreeSet::new();
+        let mut timepoints = BTreeSet::new();
+        let mut channels = BTreeSet::new();
+
+        let mut seen = HashSet::with_capacity(hm.images.len());
+        let mut wells: BTreeMap<(u16, u16), WellImages> = BTreeMap::new();
+        let mut unknown = BTreeSet::new();
+
+        for img in hm.images.iter() {
+            let id = ImageRef::from(img);
+
+            if !seen.insert(id) {
+                report.duplicates.push(id);
+            }
+            if img.row == 0 || img.row > hm.plate.rows || img.col == 0 || img.col > hm.plate.cols {
+                report.out_of_bounds.push(id);
+            }
+            if !hm.channels.contains_key(&img.channel) {
+                unknown.insert(img.channel);
+            }
+
+            fields.insert(img.field);
+            planes.insert(img.plane);
+            timepoints.insert(img.timepoint);
+            channels.insert(img.channel);
+
+            let w = wells.entry((img.row, img.col)).or_default();
+            w.fields.insert(img.field);
+            w.planes.insert(img.plane);
+            w.timepoints.insert(img.timepoint);
+            w.channels.insert(img.channel);
+            w.images
+                .insert((img.field, img.plane, img.timepoint, img.channel));
+        }
+
+        let expected = fields.len() * planes.len() * timepoints.len() * channels.len();
+
+        report.incomplete_wells = wells
+            .into_iter()
+            .filter(|(_, w)| w.images.len() < expected)
+            .map(|((row, col), w)| IncompleteWell {
+                row,
+                col,
+                missing_fields: fields.difference(&w.fields).copied().collect(),
+                missing_planes: planes.difference(&w.planes).copied().collect(),
+                missing_timepoints: timepoints.difference(&w.timepoints).copied().collect(),
+                missing_channels: channels.difference(&w.channels).copied().collect(),
+                missing_images: expected - w.images.len(),
+            })
+            .collect();
+        report.duplicates.sort();
+        report.out_of_bounds.sort();
+        report.unknown_channels = unknown.into_iter().collect();
+
+        report
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.incomplete_wells.is_empty()
+            && self.duplicates.is_empty()
+            && self.out_of_bounds.is_empty()
+            && self.unknown_channels.is_empty()
+    }
+
+    /// (row, col) of every well with a problem
+    pub fn problem_wells(&self) -> Vec<(u16, u16)> {
+        let wells: BTreeSet<_> = self
+            .incomplete_wells
+            .iter()
+            .map(|w| (w.row, w.col))
+            .chain(self.duplicates.iter().map(|i| (i.row, i.col)))
+            .collect();
+
+        wells.into_iter().collect()
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ok() {
+            return writeln!(fmt, "No problems found");
+        }
+
+        for w in self.incomplete_wells.iter() {
+            write!(
+                fmt,
+                "Well R{}C{} is missing {} images",
+                w.row, w.col, w.missing_images
+            )?;
+            if !w.missing_fields.is_empty() {
+                write!(fmt, "; fields {:?}", w.missing_fields)?;
+            }
+            if !w.missing_planes.is_empty() {
+                write!(fmt, "; planes {:?}", w.missing_planes)?;
+            }
+            if !w.missing_timepoints.is_empty() {
+                write!(fmt, "; timepoints {:?}", w.missing_timepoints)?;
+            }
+            if !w.missing_channels.is_empty() {
+                write!(fmt, "; channels {:?}", w.missing_channels)?;
+            }
+            writeln!(fmt)?;
+        }
+        for img in self.duplicates.iter() {
+            writeln!(fmt, "Duplicate image {}", img)?;
+        }
+        for img in self.out_of_bounds.iter() {
+            writeln!(fmt, "Image outside of plate {}", img)?;
+        }
+        for ch in self.unknown_channels.iter() {
+            writeln!(
+                fmt,
+                "Images refer to {:?}, which is missing from <Maps>",
+                ch
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[tauri::command]
+pub async fn validate_xml(state: State<'_, Mutex<AppState>>) -> Result<ValidationReport, AppError> {
+    let state = state.lock().await;
+
+    state
+        .report
+        .clone()
+        .ok_or_else(|| AppError::missing("Harmony information"))
+}
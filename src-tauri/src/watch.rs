@@ -0,0 +1,215 @@
+//! Watch a folder (e.g. the Harmony export share) for new exports and run an
+//! export job on each one as it shows up. Outputs go into a folder structure
+//! that mirrors the watched one, and every processed export is recorded in a
+//! log in the output folder, so restarts don't export the same plate twice.
+//! Failed exports are tried again on later passes, up to `MAX_ATTEMPTS` times.
+//!
+//! This polls instead of relying on file system notifications, since those
+//! aren't delivered reliably for network shares.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    job::ExportJob,
+    process::{run_export, Events},
+    source::INDEX_NAMES,
+};
+
+/// Name of the log of processed exports, kept in the output folder
+pub const LOG_NAME: &str = "harmony-dl-processed.log";
+
+/// Times to try exporting a plate before leaving it be
+pub const MAX_ATTEMPTS: u32 = 3;
+
+pub struct WatchConfig {
+    /// Folder to look for new exports in
+    pub dir: PathBuf,
+    /// Export to run on each plate; its XML path and output dir are replaced
+    pub job: ExportJob,
+    /// Folder that outputs are written into, mirroring `dir`
+    pub out_root: PathBuf,
+    pub interval: Duration,
+}
+
+/// Watch for new exports until the output folder can't be set up, calling
+/// `on_export` after each one with the index file and the result of the export
+pub fn watch(
+    cfg: &WatchConfig,
+    on_event: impl Fn() -> Events,
+    mut on_export: impl FnMut(&Path, &Result<()>),
+) -> Result<()> {
+    let log = cfg.out_root.join(LOG_NAME);
+    fs::create_dir_all(&cfg.out_root).context("creating output folder")?;
+
+    let (mut processed, mut failures) = read_log(&log)?;
+    // size of new index files when last seen; they are only exported once they
+    // stop growing, so that exports still being copied in are left alone
+    let mut pending: HashMap<PathBuf, u64> = HashMap::new();
+
+    loop {
+        // e.g. the share dropping out for a moment, which shouldn't end the watch
+        let indexes = match find_indexes(&cfg.dir) {
+            Ok(indexes) => indexes,
+            Err(e) => {
+                tracing::warn!(dir = %cfg.dir.display(), error = ?e, "couldn't look for new exports, trying again later");
+                thread::sleep(cfg.interval);
+                continue;
+            }
+        };
+
+        for index in indexes {
+            let attempts = failures.get(&index).copied().unwrap_or(0);
+            if processed.contains(&index) || attempts >= MAX_ATTEMPTS {
+                continue;
+            }
+
+            let Ok(size) = fs::metadata(&index).map(|m| m.len()) else {
+                continue;
+            };
+            if pending.insert(index.clone(), size) != Some(size) {
+                continue;
+            }
+            pending.remove(&index);
+
+            let res = export(cfg, &index, attempts > 0, on_event());
+            if let Err(e) = append_log(&log, &index, &res) {
+                tracing::error!(error = ?e, "couldn't record processed export");
+            }
+            on_export(&index, &res);
+
+            match res {
+                Ok(()) => {
+                    processed.insert(index);
+                }
+                Err(_) if attempts + 1 >= MAX_ATTEMPTS => {
+                    tracing::error!(index = %index.display(), attempts = attempts + 1, "giving up on export");
+                    failures.insert(index, attempts + 1);
+                }
+                Err(_) => {
+                    failures.insert(index, attempts + 1);
+                }
+            }
+        }
+
+        thread::sleep(cfg.interval);
+    }
+}
+
+/// Run the configured job on one plate, which may be a `retry` of a failed run
+fn export(cfg: &WatchConfig, index: &Path, retry: bool, on_event: Events) -> Result<()> {
+    let rel = index
+        .parent()
+        .and_then(|p| p.strip_prefix(&cfg.dir).ok())
+        .unwrap_or(Path::new(""));
+
+    let mut job = cfg.job.clone();
+    job.xml = index.to_path_buf();
+    job.output.dir = cfg.out_root.join(rel);
+    if retry {
+        job.retrying();
+    }
+    fs::create_dir_all(&job.output.dir)
+        .with_context(|| format!("creating <{}>", job.output.dir.display()))?;
+
+    let hm = job.load_export(None)?;
+    run_export(&hm, &job.filter, &job.output, on_event)
+}
+
+/// Every index file under `dir`, taking only the preferred one if a folder has
+/// several. Subfolders that can't be listed, e.g. `System Volume Information`
+/// on a share, are skipped, so only failing to list `dir` itself is an error.
+fn find_indexes(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut found: HashMap<PathBuf, (usize, PathBuf)> = HashMap::new();
+    let mut dirs = vec![dir.to_path_buf()];
+
+    while let Some(d) = dirs.pop() {
+        let entries = match fs::read_dir(&d) {
+            Ok(entries) => entries,
+            Err(e) if d != dir => {
+                tracing::warn!(dir = %d.display(), error = %e, "skipping folder that can't be listed");
+                continue;
+            }
+            Err(e) => return Err(e).with_context(|| format!("listing <{}>", d.display())),
+        };
+
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            let Ok(kind) = entry.file_type() else {
+                continue;
+            };
+
+            if kind.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+
+            let name = entry.file_name();
+            let Some(rank) = INDEX_NAMES.iter().position(|&n| name == n) else {
+                continue;
+            };
+            found
+                .entry(d.clone())
+                .and_modify(|best| {
+                    if rank < best.0 {
+                        *best = (rank, path.clone());
+                    }
+                })
+                .or_insert((rank, path));
+        }
+    }
+
+    let mut indexes: Vec<_> = found.into_values().map(|(_, p)| p).collect();
+    indexes.sort();
+    Ok(indexes)
+}
+
+/// Index files that have been exported, and the number of failed attempts at
+/// the others. Each log line is `<unix time>\t<OK|FAILED>\t<index path>`
+fn read_log(log: &Path) -> Result<(HashSet<PathBuf>, HashMap<PathBuf, u32>)> {
+    let s = match fs::read_to_string(log) {
+        Ok(s) => s,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("reading <{}>", log.display())),
+    };
+
+    let mut processed = HashSet::new();
+    let mut failures = HashMap::new();
+    for line in s.lines() {
+        match line.splitn(3, '\t').collect::<Vec<_>>()[..] {
+            [_, "OK", index] => {
+                processed.insert(PathBuf::from(index));
+            }
+            [_, _, index] => *failures.entry(PathBuf::from(index)).or_default() += 1,
+            _ => (),
+        }
+    }
+
+    Ok((processed, failures))
+}
+
+fn append_log(log: &Path, index: &Path, res: &Result<()>) -> Result<()> {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let status = match res {
+        Ok(()) => "OK",
+        Err(_) => "FAILED",
+    };
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log)
+        .and_then(|mut f| writeln!(f, "{}\t{}\t{}", time, status, index.display()))
+        .with_context(|| format!("writing <{}>", log.display()))
+}
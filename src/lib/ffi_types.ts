@@ -1,5 +1,6 @@
 export interface XmlInfo {
     name: string,
+    version: string,
     rows: number,
     cols: number,
     fields: number,
@@ -7,6 +8,7 @@ export interface XmlInfo {
     timepoints: number,
     wells: (WellInfo | null)[][],
     channels: Channel[],
+    problem_wells: [number, number][], // [r, c]
 }
 
 export interface WellInfo {
@@ -14,27 +16,111 @@ export interface WellInfo {
     col: number,
     fields: number[],
     planes: number[],
-    timepoints: number[]
+    timepoints: number[],
+    layout: WellLayout | null,
+}
+
+export interface WellLayout {
+    compound: string | null,
+    concentration: number | null,
+    unit: string | null,
+    condition: string | null,
+    replicate: number | null,
 }
 
 export interface Channel {
     id: number,
     name: string,
     res: [number, number],
-    mag: number
+    mag: number,
+    size: [number, number] | null, // width and height in pixels
+    max_intensity: number | null, // e.g. 4095 for 12 bit images
+}
+
+export interface ImageRef {
+    row: number,
+    col: number,
+    field: number,
+    plane: number,
+    timepoint: number,
+    channel: number,
+}
+
+export interface IncompleteWell {
+    row: number,
+    col: number,
+    missing_fields: number[],
+    missing_planes: number[],
+    missing_timepoints: number[],
+    missing_channels: number[],
+    missing_images: number,
 }
 
+export interface ValidationReport {
+    incomplete_wells: IncompleteWell[],
+    duplicates: ImageRef[],
+    out_of_bounds: ImageRef[],
+    unknown_channels: number[],
+}
+
+// leaving out (or nulling) a set selects everything, e.g. to only use `expr`
 export interface ImageFilter {
-    channels: number[],
-    wells: [number, number][], // [r, c]
-    fields: number[],
-    planes: number[],
+    channels?: number[] | null,
+    wells?: [number, number][] | null, // [r, c]
+    fields?: number[] | null,
+    planes?: number[] | null,
+    // per channel replacements for `fields` and `planes`, keyed by channel id
+    channel_fields?: Record<number, number[]>,
+    channel_planes?: Record<number, number[]>,
+    conditions?: string[] | null, // compounds or conditions from the plate layout
+    expr?: FilterExpr | null,
+    sampling?: FieldSampling | null,
+}
+
+export type FieldSampling =
+| { strategy: 'random', count: number, seed: number }
+| { strategy: 'center', count: number }
+| { strategy: 'evenly', count: number };
+
+export type Dim = 'row' | 'col' | 'field' | 'plane' | 'timepoint' | 'channel'
+
+export interface Span {
+    start: number,
+    end: number, // inclusive
+    step?: number,
 }
 
+export type FilterExpr =
+| { op: 'all' }
+| { op: 'edge' }
+| { op: 'in', args: { dim: Dim, spans: Span[] } }
+| { op: 'not', args: FilterExpr }
+| { op: 'and', args: FilterExpr[] }
+| { op: 'or', args: FilterExpr[] };
+
+export interface Verification {
+    retries: number,
+}
+
+// what to do about outputs that already exist
+export type ExistingFiles = 'fail' | 'overwrite' | 'skip' | 'rename'
+
 export interface OutputInfo {
     dir: string,
     action: string,
     format: string,
+    verify?: Verification | null,
+    existing?: ExistingFiles,
+}
+
+export interface DownloadPlan {
+    outputs: string[],
+    images: number,
+    wells: [number, number, number][], // [r, c, images]
+    download_bytes: number | null,
+    disk_bytes: number | null,
+    conflicts: string[],
+    existing: ExistingFiles,
 }
 
 export interface DownloadInfo {
@@ -45,22 +131,178 @@ export interface DownloadInfo {
     filter: ImageFilter,
 }
 
+export interface Progress {
+    images: number,
+    totalImages: number,
+    outputs: number,
+    totalOutputs: number,
+    bytes: number,
+    bytesPerSec: number,
+    etaSecs: number | null,
+}
+
+export interface PlaneDone {
+    r: number,
+    c: number,
+    f: number,
+    p: number,
+    t: number,
+    ch: number,
+}
+
+export interface FieldDone {
+    r: number,
+    c: number,
+    f: number,
+    t: number,
+    ch: number,
+}
+
 export type DLEvent = 
 | {
     event: 'started';
-    data: {}
+    data: {
+        images: number,
+        outputs: number,
+    }
+  }
+| {
+    event: 'progress';
+    // sent every so often, with what has been done since the last one
+    data: {
+        progress: Progress,
+        planes: PlaneDone[],
+        projected: FieldDone[],
+        written: string[], // output paths
+    };
+  }
+| {
+    event: 'lowDiskSpace';
+    data: {
+        available: number,
+        required: number,
+    };
   }
 | {
-    event: 'plane';
+    event: 'paused';
     data: {
-        r: number,
-        c: number,
-        f: number,
-        p: number,
+        reason: PauseReason,
     };
   }
+| {
+    event: 'resumed';
+    data: {}
+  }
 | {
     event: 'finished';
     data: {}
  }; 
 
+export type Phase = 'planning' | 'downloading' | 'paused' | 'finished' | 'failed'
+
+// low disk space pauses resume by themselves once there's room again
+export type PauseReason = 'user' | 'lowDiskSpace'
+
+export interface DownloadStatus {
+    id: number,
+    outdir: string,
+    phase: Phase,
+    pauseReason: PauseReason | null,
+    progress: Progress,
+    wells: [number, number, number][], // [r, c, images done]
+    errors: string[],
+}
+
+export type ParseEvent =
+| {
+    event: 'progress';
+    data: {
+        read: number,
+        total: number,
+    };
+  }
+| {
+    event: 'finished';
+    data: {
+        images: number,
+    };
+  };
+
+export interface ExportJob {
+    xml: string,
+    layout?: string | null, // plate layout CSV
+    filter: ImageFilter,
+    output: OutputInfo,
+}
+
+export type JobStatus =
+| { status: 'queued' }
+| { status: 'running', data: { processed: number } }
+| { status: 'finished' }
+| { status: 'failed', data: { error: string } };
+
+export interface QueuedJob {
+    id: number,
+    job: ExportJob,
+    status: JobStatus,
+}
+
+export interface QueueInfo {
+    jobs: QueuedJob[],
+    running: boolean,
+    load_error: string | null, // why the saved queue couldn't be read
+}
+
+export type QueueEvent =
+| { event: 'jobStarted', data: { id: number } }
+| { event: 'jobProgress', data: { id: number, processed: number, total: number } }
+| { event: 'jobFinished', data: { id: number } }
+| { event: 'jobFailed', data: { id: number, error: string } }
+| { event: 'finished' };
+
+export type AppError =
+| { kind: 'stateMissing', what: string, message: string }
+| { kind: 'xmlParse', line: number | null, column: number | null, message: string }
+| { kind: 'fetch', url: string | null, status: number | null, message: string }
+| { kind: 'decode', message: string }
+| { kind: 'io', path: string | null, io_kind: string, message: string }
+| { kind: 'other', message: string };
+
+// Commands reject with an AppError, but a failed IPC call rejects with a string
+export function errorMessage(e: unknown): string {
+    if (typeof e === 'object' && e !== null && 'message' in e) {
+        const err = e as AppError
+        if (err.kind === 'xmlParse' && err.line !== null) {
+            return `${err.message} (line ${err.line}, column ${err.column})`
+        }
+        return err.message
+    }
+    return String(e)
+}
+
+export interface QuietHours {
+    start: number, // hour, 0-23, local time
+    end: number,
+    bytes_per_sec: number, // 0 stops downloads
+}
+
+export interface BandwidthLimit {
+    bytes_per_sec: number | null,
+    quiet_hours: QuietHours | null,
+}
+
+export interface HttpSettings {
+    username: string | null,
+    // write only: saved to the OS keyring and never sent back, which is where
+    // it is looked up if not given
+    password?: string,
+    proxy: string | null,
+    root_certs: string[],
+    user_agent: string | null,
+    max_idle_connections: number | null,
+}
+
+export interface NetSettings {
+    bandwidth: BandwidthLimit,
+    http: HttpSettings,
+}
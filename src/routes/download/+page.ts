@@ -1,11 +1,14 @@
-import type { DownloadInfo } from '$lib/ffi_types';
+import type { DownloadInfo, DownloadPlan, DownloadStatus } from '$lib/ffi_types';
 import type { PageLoad } from './$types'
 
 import { invoke } from "@tauri-apps/api/core";
 
 export const load: PageLoad = async (_e) => {
     return {
-        info: await invoke<DownloadInfo>('get_dl_info')
+        info: await invoke<DownloadInfo>('get_dl_info'),
+        plan: await invoke<DownloadPlan>('plan_download'),
+        // a download started before the page was (re)loaded, if any
+        status: await invoke<DownloadStatus>('get_job_status').catch(() => null),
     }
 }
 
@@ -0,0 +1,10 @@
+import type { QueueInfo } from '$lib/ffi_types';
+import type { PageLoad } from './$types'
+
+import { invoke } from "@tauri-apps/api/core";
+
+export const load: PageLoad = async (_e) => {
+    return {
+        queue: await invoke<QueueInfo>('get_queue'),
+    }
+}